@@ -0,0 +1,387 @@
+//! Keeper auction and tip arithmetic
+//!
+//! Exclusive matching rights in a market are auctioned per interval, and
+//! traders may tip whoever fills one of their orders. The rules for both
+//! live here, on plain values: the contract loads an `Auction` or a
+//! `PriorityTip`, applies one step and stores the result back, as it does
+//! with a ledger `Position`.
+
+use stylus_sdk::alloy_primitives::{Address, U256};
+
+use crate::{
+    AuctionClosed, BidTooLow, InvalidAuctionSchedule, InvalidTipDeadline, MatchingReserved,
+    PriorityTipActive, PriorityTipNotFound, ShadowBookError, ZeroAmount,
+};
+
+/// How matching rights are cut into intervals
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Schedule {
+    /// Length of an interval in seconds (0 = no auctions)
+    pub interval: u64,
+    /// Seconds into an interval by which the winner must have matched to
+    /// keep it exclusive
+    pub grace: u64,
+}
+
+impl Schedule {
+    /// A schedule of `interval`-second intervals, rejecting a grace window
+    /// that is empty or longer than the interval
+    pub fn new(interval: u64, grace: u64) -> Result<Self, ShadowBookError> {
+        if interval != 0 && (grace == 0 || grace > interval) {
+            return Err(ShadowBookError::InvalidAuctionSchedule(
+                InvalidAuctionSchedule { interval, grace },
+            ));
+        }
+        Ok(Self { interval, grace })
+    }
+
+    /// Id of the interval running at `now` (0 while auctions are off)
+    pub fn interval_at(&self, now: u64) -> u64 {
+        match self.interval {
+            0 => 0,
+            interval => now / interval,
+        }
+    }
+}
+
+/// One market's auction for one interval
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Auction {
+    /// Highest bidder so far, the winner once the interval starts
+    /// (zero = no bids)
+    pub leader: Address,
+    pub bid: U256,
+    /// Token the bids were paid in
+    pub token: Address,
+    /// Whether the winning bid has moved to the collected fees
+    pub settled: bool,
+    /// Whether the winner has matched the market during the interval
+    pub matched: bool,
+}
+
+impl Auction {
+    /// Make `keeper`'s bid of `amount` in `token` the leading one, at time
+    /// `now`, returning the bid it beats as (keeper, amount) to refund
+    ///
+    /// Bidding on interval `interval_id` is open until it starts, and all
+    /// bids in one auction are in one token. Changes nothing on error.
+    pub fn bid(
+        &mut self,
+        schedule: &Schedule,
+        interval_id: u64,
+        now: u64,
+        keeper: Address,
+        token: Address,
+        amount: U256,
+    ) -> Result<Option<(Address, U256)>, ShadowBookError> {
+        let open = schedule.interval != 0
+            && interval_id > schedule.interval_at(now)
+            && (self.token == Address::ZERO || self.token == token);
+        if !open {
+            return Err(ShadowBookError::AuctionClosed(AuctionClosed {
+                intervalId: interval_id,
+            }));
+        }
+        if amount <= self.bid {
+            return Err(ShadowBookError::BidTooLow(BidTooLow { current: self.bid }));
+        }
+
+        let outbid = (self.leader != Address::ZERO).then_some((self.leader, self.bid));
+        self.leader = keeper;
+        self.bid = amount;
+        self.token = token;
+        Ok(outbid)
+    }
+
+    /// Take the winning bid for the collected fees, once, as (token, amount)
+    pub fn settle(&mut self) -> Option<(Address, U256)> {
+        if self.leader == Address::ZERO || self.settled {
+            return None;
+        }
+        self.settled = true;
+        Some((self.token, self.bid))
+    }
+
+    /// Check `caller` may match during interval `interval_id` at `now`
+    ///
+    /// The winner always may, and using the rights keeps them exclusive for
+    /// the rest of the interval; anyone else only once the winner let the
+    /// grace window pass without matching. An interval nobody bid on is
+    /// open to all.
+    pub fn claim(
+        &mut self,
+        schedule: &Schedule,
+        interval_id: u64,
+        now: u64,
+        caller: Address,
+    ) -> Result<(), ShadowBookError> {
+        if self.leader == Address::ZERO {
+            return Ok(());
+        }
+        if caller == self.leader {
+            self.matched = true;
+            return Ok(());
+        }
+        let start = interval_id * schedule.interval;
+        let until = if self.matched {
+            start + schedule.interval
+        } else {
+            start + schedule.grace
+        };
+        if now < until {
+            return Err(ShadowBookError::MatchingReserved(MatchingReserved {
+                keeper: self.leader,
+                until,
+            }));
+        }
+        Ok(())
+    }
+}
+
+/// A trader's tip for whoever fills one of their orders
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PriorityTip {
+    /// Trader who posted the tip (zero = none)
+    pub trader: Address,
+    pub token: Address,
+    pub amount: U256,
+    /// Smallest single fill that earns the tip, unless the fill completes
+    /// the order
+    pub min_fill: U256,
+    /// Last timestamp a fill earns the tip
+    pub deadline: u64,
+}
+
+impl PriorityTip {
+    /// Whether a tip is posted
+    pub fn is_posted(&self) -> bool {
+        self.trader != Address::ZERO
+    }
+
+    /// Check a new tip posted at `now` pays something and can still be
+    /// earned
+    pub fn check_new(&self, now: u64) -> Result<(), ShadowBookError> {
+        if self.amount == U256::ZERO {
+            return Err(ShadowBookError::ZeroAmount(ZeroAmount {}));
+        }
+        if self.deadline <= now {
+            return Err(ShadowBookError::InvalidTipDeadline(InvalidTipDeadline {
+                deadline: self.deadline,
+            }));
+        }
+        Ok(())
+    }
+
+    /// Whether one keeper fill of `filled` at `now`, leaving `remaining` of
+    /// the order, earns the tip
+    pub fn earned_by(&self, filled: U256, remaining: U256, now: u64) -> bool {
+        self.is_posted()
+            && filled != U256::ZERO
+            && now <= self.deadline
+            && (filled >= self.min_fill || remaining == U256::ZERO)
+    }
+
+    /// Check `caller` may take back the tip on order `order_id` at `now`
+    ///
+    /// Only the trader who posted it may, once the deadline has passed or
+    /// the order is no longer `open`.
+    pub fn check_reclaim(
+        &self,
+        order_id: u64,
+        caller: Address,
+        open: bool,
+        now: u64,
+    ) -> Result<(), ShadowBookError> {
+        if !self.is_posted() || self.trader != caller {
+            return Err(ShadowBookError::PriorityTipNotFound(PriorityTipNotFound {
+                orderId: order_id,
+            }));
+        }
+        if open && now <= self.deadline {
+            return Err(ShadowBookError::PriorityTipActive(PriorityTipActive {
+                deadline: self.deadline,
+            }));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: Address = Address::repeat_byte(0x70);
+    const ALICE: Address = Address::repeat_byte(0xa1);
+    const BOB: Address = Address::repeat_byte(0xb0);
+
+    /// Hour-long intervals with a ten-minute grace window
+    const HOURLY: Schedule = Schedule {
+        interval: 3_600,
+        grace: 600,
+    };
+
+    fn units(amount: u64) -> U256 {
+        U256::from(amount)
+    }
+
+    #[test]
+    fn schedules_need_a_grace_window_inside_the_interval() {
+        assert_eq!(Schedule::new(3_600, 600).ok(), Some(HOURLY));
+        assert_eq!(Schedule::new(0, 0).ok(), Some(Schedule::default()));
+        assert!(Schedule::new(3_600, 3_600).is_ok());
+        assert!(matches!(
+            Schedule::new(3_600, 0),
+            Err(ShadowBookError::InvalidAuctionSchedule(_))
+        ));
+        assert!(matches!(
+            Schedule::new(3_600, 3_601),
+            Err(ShadowBookError::InvalidAuctionSchedule(_))
+        ));
+        assert_eq!(HOURLY.interval_at(7_199), 1);
+        assert_eq!(Schedule::default().interval_at(7_199), 0);
+    }
+
+    #[test]
+    fn outbid_keepers_are_refunded_and_bidding_closes_at_the_start() {
+        let mut auction = Auction::default();
+        // Interval 2 starts at 7_200
+        let bid = |auction: &mut Auction, now, keeper, amount| {
+            auction.bid(&HOURLY, 2, now, keeper, TOKEN, units(amount))
+        };
+
+        assert_eq!(bid(&mut auction, 100, ALICE, 50).ok(), Some(None));
+        assert!(matches!(
+            bid(&mut auction, 200, BOB, 50),
+            Err(ShadowBookError::BidTooLow(err)) if err.current == units(50)
+        ));
+        assert_eq!(
+            bid(&mut auction, 300, BOB, 80).ok(),
+            Some(Some((ALICE, units(50))))
+        );
+        assert_eq!(
+            (auction.leader, auction.bid, auction.token),
+            (BOB, units(80), TOKEN)
+        );
+
+        // A bid in another token, or once the interval has started, is
+        // turned away without touching the auction
+        let before = auction;
+        let other_token = auction.bid(&HOURLY, 2, 300, ALICE, Address::ZERO, units(90));
+        assert!(matches!(
+            other_token,
+            Err(ShadowBookError::AuctionClosed(_))
+        ));
+        assert!(matches!(
+            bid(&mut auction, 7_200, ALICE, 90),
+            Err(ShadowBookError::AuctionClosed(err)) if err.intervalId == 2
+        ));
+        let off = auction.bid(&Schedule::default(), 2, 0, ALICE, TOKEN, units(90));
+        assert!(matches!(off, Err(ShadowBookError::AuctionClosed(_))));
+        assert_eq!(auction, before);
+
+        // The winning bid is taken once
+        assert_eq!(auction.settle(), Some((TOKEN, units(80))));
+        assert_eq!(auction.settle(), None);
+        assert_eq!(Auction::default().settle(), None);
+    }
+
+    #[test]
+    fn the_winner_holds_the_market_unless_it_lets_the_grace_window_lapse() {
+        let won = Auction {
+            leader: ALICE,
+            bid: units(80),
+            token: TOKEN,
+            ..Default::default()
+        };
+
+        // Others are shut out through the grace window...
+        let mut auction = won;
+        assert!(matches!(
+            auction.claim(&HOURLY, 2, 7_200 + 599, BOB),
+            Err(ShadowBookError::MatchingReserved(err))
+                if err.keeper == ALICE && err.until == 7_200 + 600
+        ));
+        // ...and let in once it lapses unused
+        assert!(auction.claim(&HOURLY, 2, 7_200 + 600, BOB).is_ok());
+        assert!(!auction.matched);
+
+        // A winner that matches in time keeps the whole interval
+        let mut auction = won;
+        assert!(auction.claim(&HOURLY, 2, 7_200 + 10, ALICE).is_ok());
+        assert!(auction.matched);
+        assert!(matches!(
+            auction.claim(&HOURLY, 2, 7_200 + 3_599, BOB),
+            Err(ShadowBookError::MatchingReserved(err)) if err.until == 10_800
+        ));
+        assert!(auction.claim(&HOURLY, 2, 7_200 + 3_599, ALICE).is_ok());
+
+        // Nobody bid: open to all
+        assert!(Auction::default().claim(&HOURLY, 2, 7_200, BOB).is_ok());
+    }
+
+    /// 100 units tipped for a fill of at least 40 by time 1_000
+    fn tip() -> PriorityTip {
+        PriorityTip {
+            trader: ALICE,
+            token: TOKEN,
+            amount: units(100),
+            min_fill: units(40),
+            deadline: 1_000,
+        }
+    }
+
+    #[test]
+    fn tips_need_an_amount_and_a_future_deadline() {
+        assert!(tip().check_new(999).is_ok());
+        assert!(matches!(
+            tip().check_new(1_000),
+            Err(ShadowBookError::InvalidTipDeadline(err)) if err.deadline == 1_000
+        ));
+        let free = PriorityTip {
+            amount: U256::ZERO,
+            ..tip()
+        };
+        assert!(matches!(
+            free.check_new(0),
+            Err(ShadowBookError::ZeroAmount(_))
+        ));
+        assert!(tip().is_posted() && !PriorityTip::default().is_posted());
+    }
+
+    #[test]
+    fn a_tip_is_earned_only_by_a_large_enough_fill_in_time() {
+        let tip = tip();
+        assert!(tip.earned_by(units(40), units(60), 1_000));
+        // Too small, unless it completes the order
+        assert!(!tip.earned_by(units(39), units(61), 500));
+        assert!(tip.earned_by(units(10), U256::ZERO, 500));
+        // No fill, or too late
+        assert!(!tip.earned_by(U256::ZERO, U256::ZERO, 500));
+        assert!(!tip.earned_by(units(100), U256::ZERO, 1_001));
+        assert!(!PriorityTip::default().earned_by(units(100), U256::ZERO, 0));
+    }
+
+    #[test]
+    fn an_unearned_tip_comes_back_after_its_deadline_or_the_order() {
+        let tip = tip();
+        assert!(matches!(
+            tip.check_reclaim(7, ALICE, true, 1_000),
+            Err(ShadowBookError::PriorityTipActive(err)) if err.deadline == 1_000
+        ));
+        assert!(tip.check_reclaim(7, ALICE, true, 1_001).is_ok());
+        // A closed order frees the tip before the deadline
+        assert!(tip.check_reclaim(7, ALICE, false, 500).is_ok());
+
+        // Nobody else may take it, and a cleared tip is gone
+        for (tip, caller) in [(tip, BOB), (PriorityTip::default(), ALICE)] {
+            assert!(matches!(
+                tip.check_reclaim(7, caller, false, 1_001),
+                Err(ShadowBookError::PriorityTipNotFound(err)) if err.orderId == 7
+            ));
+        }
+        assert!(matches!(
+            PriorityTip::default().check_reclaim(7, Address::ZERO, false, 1_001),
+            Err(ShadowBookError::PriorityTipNotFound(_))
+        ));
+    }
+}
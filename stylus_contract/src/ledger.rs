@@ -0,0 +1,282 @@
+//! Internal balance arithmetic
+//!
+//! A trader's internal balance of a token is split into a free part and a
+//! part reserved by their open ledger-backed orders, and each such order
+//! tracks its own reservation. The rules for moving value between those
+//! live here, on plain values: the contract loads a `Position`, applies one
+//! step and stores the result back, so the ledger can be checked without
+//! contract storage.
+
+use stylus_sdk::alloy_primitives::U256;
+
+/// One ledger-backed order's view of its trader's balance of its token_in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Position {
+    /// The trader's whole internal balance of the token
+    pub balance: U256,
+    /// Part of `balance` reserved by all of the trader's open orders
+    pub reserved: U256,
+    /// Part of `reserved` held for this order
+    pub order_reserve: U256,
+}
+
+impl Position {
+    /// Part of the balance no open order has reserved
+    pub fn free(&self) -> U256 {
+        self.balance.saturating_sub(self.reserved)
+    }
+
+    /// Move the order's reservation to `target`, taking any increase from
+    /// the free balance
+    ///
+    /// Returns None, changing nothing, if the free balance can't cover the
+    /// increase.
+    pub fn set_reserve(&mut self, target: U256) -> Option<()> {
+        if target > self.order_reserve {
+            let increase = target - self.order_reserve;
+            if self.free() < increase {
+                return None;
+            }
+            self.reserved += increase;
+        } else {
+            self.reserved = self.reserved.saturating_sub(self.order_reserve - target);
+        }
+        self.order_reserve = target;
+        Some(())
+    }
+
+    /// Return whatever the order still reserves to the free balance,
+    /// returning the amount released
+    pub fn release(&mut self) -> U256 {
+        let leftover = self.order_reserve;
+        self.reserved = self.reserved.saturating_sub(leftover);
+        self.order_reserve = U256::ZERO;
+        leftover
+    }
+
+    /// Take up to `amount` out of the balance for a fill, returning what
+    /// was actually paid
+    ///
+    /// The order's reservation is used first. A buy's per-fill rounding can
+    /// ask for a unit more than it reserved; that comes from the free
+    /// balance, and if even that is empty the payment falls short rather
+    /// than crediting tokens nobody paid.
+    pub fn pay(&mut self, amount: U256) -> U256 {
+        let from_reserve = amount.min(self.order_reserve);
+        let paid = from_reserve + (amount - from_reserve).min(self.free());
+        self.reserved = self.reserved.saturating_sub(from_reserve);
+        self.order_reserve -= from_reserve;
+        self.balance -= paid;
+        paid
+    }
+}
+
+/// Split the base a seller actually paid between the buyer and the fee, as
+/// (credited to the buyer, kept as fee)
+///
+/// The fee comes out of what was paid, so a short payment can never leave
+/// the contract owing more fee than it received.
+pub fn split_fee(base_paid: U256, fee: U256) -> (U256, U256) {
+    let kept = fee.min(base_paid);
+    (base_paid - kept, kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn units(amount: u64) -> U256 {
+        U256::from(amount)
+    }
+
+    /// A trader's balance of one token with nothing reserved yet
+    fn deposited(amount: u64) -> Position {
+        Position {
+            balance: units(amount),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reserve_and_free_split() {
+        let mut position = deposited(100);
+        assert_eq!(position.set_reserve(units(60)), Some(()));
+        assert_eq!((position.reserved, position.free()), (units(60), units(40)));
+
+        // Lowering the reservation hands the difference back
+        assert_eq!(position.set_reserve(units(25)), Some(()));
+        assert_eq!((position.reserved, position.free()), (units(25), units(75)));
+
+        // An increase the free balance can't cover changes nothing
+        let before = position;
+        assert_eq!(position.set_reserve(units(101)), None);
+        assert_eq!(position, before);
+
+        assert_eq!(position.release(), units(25));
+        assert_eq!(position.release(), U256::ZERO);
+        assert_eq!(position, deposited(100));
+    }
+
+    #[test]
+    fn payments_use_the_reservation_then_the_free_balance() {
+        let mut position = deposited(50);
+        position.set_reserve(units(30)).unwrap();
+
+        assert_eq!(position.pay(units(20)), units(20));
+        assert_eq!(position.order_reserve, units(10));
+        assert_eq!(
+            (position.balance, position.reserved),
+            (units(30), units(10))
+        );
+
+        // Rounding past the reservation draws on the free balance
+        assert_eq!(position.pay(units(12)), units(12));
+        assert_eq!(position.order_reserve, U256::ZERO);
+        assert_eq!(
+            (position.balance, position.reserved),
+            (units(18), U256::ZERO)
+        );
+
+        // and falls short once that is gone too
+        assert_eq!(position.pay(units(25)), units(18));
+        assert_eq!(position, Position::default());
+    }
+
+    #[test]
+    fn another_orders_reservation_is_never_spent() {
+        // This order reserves 40 and another of the trader's orders 30
+        let mut position = Position {
+            balance: units(100),
+            reserved: units(70),
+            order_reserve: units(40),
+        };
+
+        // Asked for 90, it can only take its own 40 and the 30 free
+        assert_eq!(position.pay(units(90)), units(70));
+        assert_eq!(position.balance, units(30));
+        assert_eq!(position.reserved, units(30));
+        assert_eq!(position.free(), U256::ZERO);
+    }
+
+    #[test]
+    fn fee_is_kept_only_from_what_was_paid() {
+        assert_eq!(split_fee(units(1_000), units(3)), (units(997), units(3)));
+        assert_eq!(split_fee(units(2), units(3)), (U256::ZERO, units(2)));
+        assert_eq!(split_fee(U256::ZERO, units(3)), (U256::ZERO, U256::ZERO));
+    }
+
+    /// Settle a fill the way `settle_internal` does, returning the fee kept
+    ///
+    /// Positions are (seller's base, buyer's quote); credits land in
+    /// (seller's quote, buyer's base).
+    fn settle(
+        seller: &mut Position,
+        buyer: &mut Position,
+        credits: &mut (U256, U256),
+        amount: U256,
+        quote_amount: U256,
+        fee: U256,
+    ) -> U256 {
+        let base_paid = seller.pay(amount);
+        let quote_paid = buyer.pay(quote_amount);
+        credits.0 += quote_paid;
+        let (credit, kept) = split_fee(base_paid, fee);
+        credits.1 += credit;
+        kept
+    }
+
+    #[test]
+    fn deposit_fill_cancel_withdraw_round_trip() {
+        // Seller deposits 1_000 base and offers it all; buyer deposits 5_000
+        // quote and bids for 1_000 base at 5
+        let (mut seller, mut buyer) = (deposited(1_000), deposited(5_000));
+        seller.set_reserve(units(1_000)).unwrap();
+        buyer.set_reserve(units(5_000)).unwrap();
+
+        // A partial fill of 400 base for 2_000 quote; 30 bps rounds to a fee of 1
+        let mut credits = (U256::ZERO, U256::ZERO);
+        let fee = settle(
+            &mut seller,
+            &mut buyer,
+            &mut credits,
+            units(400),
+            units(2_000),
+            units(1),
+        );
+        assert_eq!(fee, units(1));
+        assert_eq!(credits, (units(2_000), units(399)));
+        assert_eq!(
+            seller,
+            Position {
+                balance: units(600),
+                reserved: units(600),
+                order_reserve: units(600),
+            }
+        );
+        assert_eq!(buyer.order_reserve, units(3_000));
+
+        // Both cancel, and everything left is free to withdraw
+        assert_eq!(seller.release(), units(600));
+        assert_eq!(buyer.release(), units(3_000));
+        assert_eq!(seller.free(), units(600));
+        assert_eq!(buyer.free(), units(3_000));
+        for position in [&mut seller, &mut buyer] {
+            let free = position.free();
+            position.balance -= free;
+            assert_eq!(*position, Position::default());
+        }
+    }
+
+    #[test]
+    fn fee_pot_never_exceeds_fees_received() {
+        let mut seed = 0x1ed6e5u64;
+        let mut next = move |bound: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            U256::from(seed % bound)
+        };
+
+        for _ in 0..500 {
+            let (base_in, quote_in) = (next(10_000), next(10_000));
+            let mut seller = Position {
+                balance: base_in,
+                ..Default::default()
+            };
+            let mut buyer = Position {
+                balance: quote_in,
+                ..Default::default()
+            };
+            // Reservations may fall short of what fills ask for
+            seller
+                .set_reserve(base_in - next(1 + base_in.to::<u64>()))
+                .unwrap();
+            buyer
+                .set_reserve(quote_in - next(1 + quote_in.to::<u64>()))
+                .unwrap();
+
+            let mut credits = (U256::ZERO, U256::ZERO);
+            let mut pot = U256::ZERO;
+            let mut base_paid = U256::ZERO;
+            for _ in 0..4 {
+                let amount = next(4_000);
+                let before = seller.balance;
+                pot += settle(
+                    &mut seller,
+                    &mut buyer,
+                    &mut credits,
+                    amount,
+                    next(8_000),
+                    amount / U256::from(100) + next(3),
+                );
+                base_paid += before - seller.balance;
+            }
+
+            // Nothing is created: every unit is still held, credited or fee
+            assert!(pot <= base_paid);
+            assert_eq!(seller.balance + credits.1 + pot, base_in);
+            assert_eq!(buyer.balance + credits.0, quote_in);
+            assert!(seller.reserved <= seller.balance && buyer.reserved <= buyer.balance);
+        }
+    }
+}
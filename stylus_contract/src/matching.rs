@@ -0,0 +1,2299 @@
+//! Host-independent matching core
+//!
+//! Nothing in this module reads storage or calls into the Stylus host, so the
+//! contract and the off-chain matcher run exactly the same rules.
+//!
+//! Prices are whole quote tokens per whole base token scaled by PRICE_SCALE
+//! (times 10^exponent in markets with a price exponent, see
+//! `MatchConfig::price_scale`), so settling a fill converts through both
+//! tokens' decimals. The only
+//! rounding there is in `quote_owed`, and it always favours the resting
+//! order: the earlier of the two orders in a book scan, or the book order a
+//! market order takes.
+//!
+//! Wherever resting orders are ranked (market orders, pro-rata levels) the
+//! priority is `priority_cmp`: best price, then displayed before fully
+//! hidden liquidity at that price, then oldest first within each class.
+//!
+//! Book scans run over orders in time priority (see `canonicalize`), never
+//! in storage slot order, so the fills a pass produces depend only on the
+//! orders themselves and not on where compaction happened to move them.
+
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::ops::Range;
+use stylus_sdk::alloy_primitives::{Address, B256, U256};
+
+use crate::{
+    index_to_u64, pair_key, MatchResult, MatchSummary, Order, BPS_DENOMINATOR,
+    DEFAULT_TOKEN_DECIMALS, PRICE_SCALE,
+};
+
+/// Contract settings the matching rules depend on
+#[derive(Clone, Debug, Default)]
+pub struct MatchConfig {
+    /// Trading fee in basis points taken from every fill
+    pub fee_bps: u64,
+    /// Whether orders from the same trader may match each other
+    pub allow_self_trade: bool,
+    /// Trader pairs (by `pair_key` of the two traders) that may not match
+    /// each other at all; filled from flagged pairs in strict wash-trade mode
+    pub blocked_counterparties: Vec<B256>,
+    /// Split fills across all resting orders at a price level in proportion
+    /// to their size, instead of oldest first
+    pub pro_rata: bool,
+    /// Decimals of the tokens being matched; any token missing here is taken
+    /// to have DEFAULT_TOKEN_DECIMALS
+    pub token_decimals: Vec<(Address, u8)>,
+    /// Block timestamp of the pass; orders scheduled after it can't match
+    pub now: u64,
+    /// Largest move from the reference price a fill may print, in basis
+    /// points (0 = circuit breaker off)
+    pub breaker_bps: u64,
+    /// Last trade price before the pass, as (base, quote, price), for each
+    /// direction being matched; used by the circuit breaker and order collars
+    pub reference_prices: Vec<(Address, Address, U256)>,
+    /// Extra decimal places of limit prices per market, as (pair_key,
+    /// exponent); any market missing here uses 0
+    pub price_exponents: Vec<(B256, u8)>,
+}
+
+impl MatchConfig {
+    /// Whether two traders are barred from trading with each other
+    pub fn counterparties_blocked(&self, trader_a: Address, trader_b: Address) -> bool {
+        !self.blocked_counterparties.is_empty()
+            && self
+                .blocked_counterparties
+                .contains(&pair_key(trader_a, trader_b))
+    }
+
+    /// Whether `order` sits out matching for now: an untriggered trigger
+    /// order, a scheduled order before its activation time, or one its
+    /// trader has signalled a cancel for
+    pub fn dormant(&self, order: &Order) -> bool {
+        order.awaiting_trigger() || order.pending_at(self.now) || order.cancel_pending
+    }
+
+    /// Whether a fill at `price` in the base/quote market strays further
+    /// from the reference price than the circuit breaker allows
+    ///
+    /// A market with no reference price yet never trips.
+    pub fn breaker_trips(&self, base: Address, quote: Address, price: U256) -> bool {
+        self.strays(base, quote, price, self.breaker_bps)
+    }
+
+    /// Whether a fill at `price` would break `order`'s own collar
+    ///
+    /// As with the circuit breaker, the collar is measured from the market's
+    /// reference price and a market without one yet never collars.
+    pub fn outside_collar(&self, order: &Order, price: U256) -> bool {
+        let (base, quote) = order.base_quote();
+        self.strays(base, quote, price, order.collar_bps)
+    }
+
+    /// Whether `price` is more than `bps` basis points from the base/quote
+    /// reference price (never, with `bps` 0 or no reference)
+    fn strays(&self, base: Address, quote: Address, price: U256, bps: u64) -> bool {
+        if bps == 0 {
+            return false;
+        }
+        let reference = self
+            .reference_prices
+            .iter()
+            .find(|(b, q, _)| *b == base && *q == quote)
+            .map_or(U256::ZERO, |(_, _, price)| *price);
+        if reference == U256::ZERO {
+            return false;
+        }
+        let deviation = if price > reference {
+            price - reference
+        } else {
+            reference - price
+        };
+        deviation.saturating_mul(U256::from(BPS_DENOMINATOR))
+            > reference.saturating_mul(U256::from(bps))
+    }
+
+    /// Decimals of `token`
+    pub fn decimals(&self, token: Address) -> u8 {
+        self.token_decimals
+            .iter()
+            .find(|(known, _)| *known == token)
+            .map_or(DEFAULT_TOKEN_DECIMALS, |(_, decimals)| *decimals)
+    }
+
+    /// What one whole quote token per whole base token is written as in the
+    /// base/quote market: PRICE_SCALE times 10^(its price exponent)
+    pub fn price_scale(&self, base: Address, quote: Address) -> U256 {
+        let key = pair_key(base, quote);
+        let exponent = self
+            .price_exponents
+            .iter()
+            .find(|(known, _)| *known == key)
+            .map_or(0, |(_, exponent)| *exponent);
+        PRICE_SCALE * U256::from(10).pow(U256::from(exponent))
+    }
+
+    /// One whole token in `token`'s base units (10^decimals)
+    fn unit(&self, token: Address) -> U256 {
+        U256::from(10).pow(U256::from(self.decimals(token)))
+    }
+}
+
+/// Raw exchange rate behind a scaled `price` in the base/quote market:
+/// quote units per base unit as a reduced (num, den) fraction
+///
+/// Returns None if the rate can't be represented.
+pub fn unit_rate(
+    config: &MatchConfig,
+    base: Address,
+    quote: Address,
+    price: U256,
+) -> Option<(U256, U256)> {
+    let num = price.checked_mul(config.unit(quote))?;
+    let den = config
+        .unit(base)
+        .checked_mul(config.price_scale(base, quote))?;
+    Some(reduce(num, den))
+}
+
+/// Divide a fraction through by its greatest common divisor
+fn reduce(num: U256, den: U256) -> (U256, U256) {
+    let divisor = num.gcd(den);
+    if divisor == U256::ZERO {
+        (num, den)
+    } else {
+        (num / divisor, den / divisor)
+    }
+}
+
+/// Quote units owed for `amount` base units of `resting`'s market at `price`
+///
+/// Exact quotients are returned as is; otherwise the result rounds in favour
+/// of the resting order: up when it sells (it receives the quote), down when
+/// it buys (it pays the quote). Returns None on overflow.
+pub fn quote_owed(
+    config: &MatchConfig,
+    resting: &Order,
+    amount: U256,
+    price: U256,
+) -> Option<U256> {
+    let (base, quote) = resting.base_quote();
+    let (num, den) = unit_rate(config, base, quote, price)?;
+    let product = amount.checked_mul(num)?;
+    let owed = product / den;
+    let inexact = product % den != U256::ZERO;
+    if inexact && !resting.is_buy {
+        owed.checked_add(U256::from(1))
+    } else {
+        Some(owed)
+    }
+}
+
+/// Most `order` can pay for `amount` base units at `price`, in its token_in:
+/// the base itself for a sell, the quote rounded up for a buy
+///
+/// Returns None on overflow.
+pub fn reserve_needed(
+    config: &MatchConfig,
+    order: &Order,
+    amount: U256,
+    price: U256,
+) -> Option<U256> {
+    if !order.is_buy {
+        return Some(amount);
+    }
+    let (base, quote) = order.base_quote();
+    let (num, den) = unit_rate(config, base, quote, price)?;
+    let product = amount.checked_mul(num)?;
+    let needed = product / den;
+    if product % den != U256::ZERO {
+        needed.checked_add(U256::from(1))
+    } else {
+        Some(needed)
+    }
+}
+
+/// Scaled price of exchanging `base_amount` base units for `quote_amount`
+/// quote units in the base/quote market, rounded down
+fn scaled_price(
+    config: &MatchConfig,
+    base: Address,
+    quote: Address,
+    base_amount: U256,
+    quote_amount: U256,
+) -> Option<U256> {
+    let num = quote_amount
+        .checked_mul(config.unit(base))?
+        .checked_mul(config.price_scale(base, quote))?;
+    Some(num / base_amount.checked_mul(config.unit(quote))?)
+}
+
+/// Why two orders can't match, as reported by `match_block`
+///
+/// Codes are stable: the contract's `eligibility` view returns them as u8,
+/// with 0 meaning the pair can match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MatchBlock {
+    SameSide = 1,
+    /// A trigger order not yet triggered, or a scheduled order not yet active
+    Dormant = 2,
+    SelfTrade = 3,
+    /// The two traders are flagged for wash trading and strict mode is on
+    CounterpartiesBlocked = 4,
+    /// One order settles from the internal balance ledger and the other not
+    BalanceSourceMismatch = 5,
+    TokenMismatch = 6,
+    PricesDontCross = 7,
+    OutsideCollar = 8,
+    BelowMinFill = 9,
+    /// The pair can match but the circuit breaker stops the fill; only
+    /// reported by `eligibility`, since the breaker runs after `can_match`
+    CircuitBreaker = 10,
+}
+
+/// Audit reason for a candidate that could have matched but ranked behind
+/// the filled order, or was still waiting when the taker ran out
+pub const OUTRANKED: u8 = 0;
+
+/// Orders passed over when `orders[i]` filled against `orders[j]`, best
+/// first, as (row, reason), at most `limit` of them
+///
+/// A candidate rests on `orders[j]`'s side of the same market at the same or
+/// a better price for `orders[i]` and did not fill against it in the pass
+/// (`filled_with_i` lists the rows that did). The reason is the MatchBlock
+/// code that kept it out, or OUTRANKED. `orders` is the book as it stood
+/// before the pass; fully hidden orders are never reported.
+pub fn passed_over(
+    config: &MatchConfig,
+    orders: &[Order],
+    i: usize,
+    j: usize,
+    filled_with_i: &[usize],
+    limit: usize,
+) -> Vec<(usize, u8)> {
+    let (taker, filled) = (&orders[i], &orders[j]);
+    let mut candidates: Vec<usize> = (0..orders.len())
+        .filter(|&k| k != i && k != j && !filled_with_i.contains(&k))
+        .filter(|&k| {
+            let order = &orders[k];
+            order.amount != U256::ZERO
+                && !order.fully_hidden
+                && order.is_buy == filled.is_buy
+                && order.token_in == filled.token_in
+                && order.token_out == filled.token_out
+                && if filled.is_buy {
+                    order.limit_price >= filled.limit_price
+                } else {
+                    order.limit_price <= filled.limit_price
+                }
+        })
+        .collect();
+    candidates.sort_by(|&a, &b| priority_cmp(taker.is_buy, &orders[a], &orders[b]));
+    candidates.truncate(limit);
+
+    candidates
+        .into_iter()
+        .map(|k| {
+            let reason =
+                match_block(config, taker, &orders[k]).map_or(OUTRANKED, |block| block as u8);
+            (k, reason)
+        })
+        .collect()
+}
+
+/// Count `count` pairs passed over for `block` in `summary.skipped`
+pub fn record_skips(summary: &mut MatchSummary, block: MatchBlock, count: u64) {
+    let code = block as usize;
+    if summary.skipped.len() <= code {
+        summary.skipped.resize(code + 1, 0);
+    }
+    summary.skipped[code] += count;
+}
+
+/// Check if two orders can match
+pub fn can_match(config: &MatchConfig, order_a: &Order, order_b: &Order) -> bool {
+    match_block(config, order_a, order_b).is_none()
+}
+
+/// The first rule that stops two orders matching, or None if they can
+///
+/// `can_match` is exactly `match_block(..).is_none()`.
+pub fn match_block(config: &MatchConfig, order_a: &Order, order_b: &Order) -> Option<MatchBlock> {
+    // Orders must be opposite sides
+    if order_a.is_buy == order_b.is_buy {
+        return Some(MatchBlock::SameSide);
+    }
+
+    // Trigger and scheduled orders sit out until activated
+    if config.dormant(order_a) || config.dormant(order_b) {
+        return Some(MatchBlock::Dormant);
+    }
+
+    // Self-trades are wash volume unless the owner explicitly allows them
+    if order_a.trader == order_b.trader && !config.allow_self_trade {
+        return Some(MatchBlock::SelfTrade);
+    }
+
+    // Pairs flagged for wash trading are cut off entirely in strict mode
+    if config.counterparties_blocked(order_a.trader, order_b.trader) {
+        return Some(MatchBlock::CounterpartiesBlocked);
+    }
+
+    // Ledger-backed orders settle inside the contract, so they can only
+    // trade with each other
+    if order_a.internal_balance != order_b.internal_balance {
+        return Some(MatchBlock::BalanceSourceMismatch);
+    }
+
+    // Token pairs must match (reversed)
+    let tokens_match =
+        order_a.token_in == order_b.token_out && order_a.token_out == order_b.token_in;
+
+    if !tokens_match {
+        return Some(MatchBlock::TokenMismatch);
+    }
+
+    // Determine which is buy and which is sell
+    let (buy_order, sell_order) = if order_a.is_buy {
+        (order_a, order_b)
+    } else {
+        (order_b, order_a)
+    };
+
+    // Buy price must be >= sell price for match
+    if buy_order.limit_price < sell_order.limit_price {
+        return Some(MatchBlock::PricesDontCross);
+    }
+
+    // Neither side may be filled outside its own collar
+    let price = midpoint_price(buy_order, sell_order);
+    if config.outside_collar(order_a, price) || config.outside_collar(order_b, price) {
+        return Some(MatchBlock::OutsideCollar);
+    }
+
+    // The fill must meet both sides' minimum fill size
+    let matched_amount = order_a.amount.min(order_b.amount);
+    if matched_amount < order_a.min_fill_amount || matched_amount < order_b.min_fill_amount {
+        return Some(MatchBlock::BelowMinFill);
+    }
+
+    None
+}
+
+/// Execute a single match between two orders
+///
+/// `order_a` is the resting side: the earlier order in time priority.
+pub fn execute_single_match(
+    config: &MatchConfig,
+    order_a: &Order,
+    order_b: &Order,
+) -> Option<MatchResult> {
+    settle_pair(config, order_a, order_b, order_a.amount.min(order_b.amount))
+}
+
+/// Price a fill of `matched_amount` between two crossing orders, `order_a`
+/// resting
+fn settle_pair(
+    config: &MatchConfig,
+    order_a: &Order,
+    order_b: &Order,
+    matched_amount: U256,
+) -> Option<MatchResult> {
+    let (buy_order, sell_order) = if order_a.is_buy {
+        (order_a, order_b)
+    } else {
+        (order_b, order_a)
+    };
+
+    let execution_price = midpoint_price(buy_order, sell_order);
+
+    Some(MatchResult {
+        buy_order_id: buy_order.id,
+        sell_order_id: sell_order.id,
+        execution_price,
+        amount: matched_amount,
+        quote_amount: quote_owed(config, order_a, matched_amount, execution_price)?,
+        fee: fill_fee(config, matched_amount)?,
+        gas_used: U256::ZERO, // Measured by the caller once the fill is applied
+        flagged: false,
+    })
+}
+
+/// Execution price of a pairwise fill: the midpoint of the two limits
+///
+/// Steps up from the sell price so prices near U256::MAX can't overflow; the
+/// result stays in [sell, buy].
+pub fn midpoint_price(buy_order: &Order, sell_order: &Order) -> U256 {
+    let spread = buy_order.limit_price.saturating_sub(sell_order.limit_price);
+    sell_order.limit_price + spread / U256::from(2)
+}
+
+/// Fee owed on a fill of `amount`, or None if the fee can't be priced
+/// without overflowing (the fill is then rejected)
+///
+/// The fee is carved out of the matched amount and rounds down, so a fill
+/// never consumes more than either order's remaining size.
+pub fn fill_fee(config: &MatchConfig, amount: U256) -> Option<U256> {
+    Some(amount.checked_mul(U256::from(config.fee_bps))? / U256::from(BPS_DENOMINATOR))
+}
+
+/// Amount an order keeps resting after a fill
+///
+/// A remainder below the order's own minimum fill can never match again,
+/// so it is cleared rather than left as dust in the book.
+pub fn remaining_after_fill(order: &Order, filled: U256) -> U256 {
+    let remaining = order.amount.saturating_sub(filled);
+    if remaining < order.min_fill_amount {
+        U256::ZERO
+    } else {
+        remaining
+    }
+}
+
+/// Whether changing `order` to `new_amount` at `new_limit_price` costs it
+/// its place in time priority
+///
+/// Growing the size or making the price more aggressive (higher buy, lower
+/// sell) counts as a fresh submission; shrinking the size or backing the
+/// price off keeps the original timestamp, so time priority can't be gamed.
+pub fn modify_requeues(order: &Order, new_amount: U256, new_limit_price: U256) -> bool {
+    let more_aggressive = if order.is_buy {
+        new_limit_price > order.limit_price
+    } else {
+        new_limit_price < order.limit_price
+    };
+    new_amount > order.amount || more_aggressive
+}
+
+/// Split `amount` across a price level's resting sizes in proportion to size
+///
+/// `sizes` must be in time priority, oldest first, and sum to at least
+/// `amount`. Each order gets the floor of its share, then the rounding
+/// remainder goes to the oldest orders, each up to its own size. The result
+/// always sums to exactly `amount`; small amounts can leave orders with 0.
+pub fn allocate_pro_rata(amount: U256, sizes: &[U256]) -> Vec<U256> {
+    let total = sizes
+        .iter()
+        .fold(U256::ZERO, |sum, size| sum.saturating_add(*size));
+    if total == U256::ZERO {
+        return alloc::vec![U256::ZERO; sizes.len()];
+    }
+
+    // A share too large to compute exactly falls back to 0 and is made up
+    // from the remainder below
+    let mut allocations: Vec<U256> = sizes
+        .iter()
+        .map(|size| {
+            amount
+                .checked_mul(*size)
+                .map_or(U256::ZERO, |product| product / total)
+        })
+        .collect();
+
+    let allocated = allocations
+        .iter()
+        .fold(U256::ZERO, |sum, share| sum + *share);
+    let mut remainder = amount.saturating_sub(allocated);
+    for (share, size) in allocations.iter_mut().zip(sizes) {
+        if remainder == U256::ZERO {
+            break;
+        }
+        let extra = remainder.min(size.saturating_sub(*share));
+        *share += extra;
+        remainder -= extra;
+    }
+
+    allocations
+}
+
+/// Hide every iceberg order's reserve for a matching pass, leaving only its
+/// visible tranche (see `Order::visible_amount`) in `amount`
+///
+/// Returns each order's hidden reserve, to hand back to `restore_reserves`
+/// once the pass is done. Tranches refresh between passes, never within one,
+/// so a pass fills at most one tranche of each iceberg.
+pub fn hide_reserves(orders: &mut [Order]) -> Vec<U256> {
+    orders
+        .iter_mut()
+        .map(|order| {
+            let visible = order.visible_amount();
+            let hidden = order.amount - visible;
+            order.amount = visible;
+            hidden
+        })
+        .collect()
+}
+
+/// Add back the reserves `hide_reserves` took out, returning the positions
+/// of icebergs whose visible tranche the pass used up
+///
+/// Those icebergs show a fresh tranche from now on and take time priority
+/// from `now`, behind every order already resting at their price.
+pub fn restore_reserves(orders: &mut [Order], hidden: &[U256], now: u64) -> Vec<usize> {
+    let mut refreshed: Vec<usize> = Vec::new();
+    for (i, order) in orders.iter_mut().enumerate() {
+        if hidden[i] == U256::ZERO {
+            continue;
+        }
+        if order.amount == U256::ZERO {
+            order.timestamp = now;
+            refreshed.push(i);
+        }
+        order.amount += hidden[i];
+    }
+    refreshed
+}
+
+/// Group orders into per-pair books, keeping first-seen book order and the
+/// relative order of orders within each book
+///
+/// This is how the off-chain matcher and replays rebuild the contract's
+/// books from a flat list of orders.
+pub fn split_books(orders: impl IntoIterator<Item = Order>) -> Vec<Vec<Order>> {
+    let mut keys: Vec<B256> = Vec::new();
+    let mut books: Vec<Vec<Order>> = Vec::new();
+
+    for order in orders {
+        let key = pair_key(order.token_in, order.token_out);
+        match keys.iter().position(|k| *k == key) {
+            Some(book) => books[book].push(order),
+            None => {
+                keys.push(key);
+                books.push(alloc::vec![order]);
+            }
+        }
+    }
+
+    books
+}
+
+/// Run the matching loop over an in-memory copy of one book
+///
+/// `orders` must be in time priority (see `canonicalize`). Fills are applied to `orders` as they
+/// happen and returned as (index_a, index_b, result); `on_fill` sees each
+/// result first so the caller can attach host-side metrics.
+pub fn scan_orders<F>(
+    config: &MatchConfig,
+    orders: &mut [Order],
+    summary: &mut MatchSummary,
+    on_fill: F,
+) -> Vec<(usize, usize, MatchResult)>
+where
+    F: FnMut(&mut MatchResult),
+{
+    scan_rows(config, orders, 0, u64::MAX, summary, on_fill).0
+}
+
+/// Run part of the matching loop, starting at outer row `start`
+///
+/// Each row matches one order against every later order, exactly as in
+/// `scan_orders`, so running consecutive chunks over the same book produces
+/// the same fills as one full scan. Rows are never split: the scan stops
+/// before the first row that would begin once `max_comparisons` have been
+/// made, but always runs at least one. Returns the fills and the next row to
+/// scan (`orders.len()` once the pass is complete).
+pub fn scan_rows<F>(
+    config: &MatchConfig,
+    orders: &mut [Order],
+    start: usize,
+    max_comparisons: u64,
+    summary: &mut MatchSummary,
+    mut on_fill: F,
+) -> (Vec<(usize, usize, MatchResult)>, usize)
+where
+    F: FnMut(&mut MatchResult),
+{
+    let mut fills: Vec<(usize, usize, MatchResult)> = Vec::new();
+    let order_count = orders.len();
+    summary.orders_scanned += index_to_u64(order_count);
+
+    // CRITICAL: This O(n²) loop would be IMPOSSIBLE in Solidity
+    // But in Stylus, we can scan 100+ orders in milliseconds for pennies
+    let comparisons_before = summary.comparisons;
+    for i in start..order_count {
+        if i > start && summary.comparisons - comparisons_before >= max_comparisons {
+            return (fills, i);
+        }
+        if config.pro_rata {
+            scan_pro_rata(config, orders, i, summary, &mut on_fill, &mut fills);
+        } else {
+            scan_price_time(config, orders, i, summary, &mut on_fill, &mut fills);
+        }
+    }
+
+    (fills, order_count)
+}
+
+/// One outer step of `scan_rows`: match order `i` against every later order
+/// in time priority
+fn scan_price_time<F>(
+    config: &MatchConfig,
+    orders: &mut [Order],
+    i: usize,
+    summary: &mut MatchSummary,
+    on_fill: &mut F,
+    fills: &mut Vec<(usize, usize, MatchResult)>,
+) where
+    F: FnMut(&mut MatchResult),
+{
+    for j in (i + 1)..orders.len() {
+        // Stop once order i is used up (amount = 0)
+        if orders[i].amount == U256::ZERO {
+            break;
+        }
+
+        // Skip if already matched
+        if orders[j].amount == U256::ZERO {
+            continue;
+        }
+
+        // Check if orders can match
+        summary.comparisons += 1;
+        if let Some(block) = match_block(config, &orders[i], &orders[j]) {
+            record_skips(summary, block, 1);
+            continue;
+        }
+
+        // Execute the match, keeping any remainder of order i
+        // available for the rest of the scan
+        if let Some(mut result) = execute_single_match(config, &orders[i], &orders[j]) {
+            if breaker_blocks(config, &orders[i], &result) {
+                summary.breaker_trips += 1;
+                record_skips(summary, MatchBlock::CircuitBreaker, 1);
+                continue;
+            }
+            orders[i].amount = remaining_after_fill(&orders[i], result.amount);
+            orders[j].amount = remaining_after_fill(&orders[j], result.amount);
+            on_fill(&mut result);
+
+            summary.matches_found += 1;
+            fills.push((i, j, result));
+        }
+    }
+}
+
+/// Pro-rata counterpart of `scan_price_time`
+///
+/// Every later order that can match order `i` is grouped by price, best
+/// level first (displayed size before hidden size at each price), and each
+/// group's matched amount is split across its orders with
+/// `allocate_pro_rata`. An allocation below either side's minimum fill
+/// is left unfilled rather than moved to another order.
+fn scan_pro_rata<F>(
+    config: &MatchConfig,
+    orders: &mut [Order],
+    i: usize,
+    summary: &mut MatchSummary,
+    on_fill: &mut F,
+    fills: &mut Vec<(usize, usize, MatchResult)>,
+) where
+    F: FnMut(&mut MatchResult),
+{
+    if orders[i].amount == U256::ZERO {
+        return;
+    }
+
+    let mut candidates: Vec<usize> = Vec::new();
+    for j in (i + 1)..orders.len() {
+        if orders[j].amount == U256::ZERO {
+            continue;
+        }
+        summary.comparisons += 1;
+        match match_block(config, &orders[i], &orders[j]) {
+            Some(block) => record_skips(summary, block, 1),
+            None => candidates.push(j),
+        }
+    }
+
+    let incoming_buys = orders[i].is_buy;
+    candidates.sort_by(|&a, &b| priority_cmp(incoming_buys, &orders[a], &orders[b]));
+
+    for range in price_levels(orders, &candidates) {
+        let level = &candidates[range];
+        let remaining = orders[i].amount;
+        if remaining == U256::ZERO {
+            break;
+        }
+
+        let sizes: Vec<U256> = level.iter().map(|&j| orders[j].amount).collect();
+        let level_size = sizes
+            .iter()
+            .fold(U256::ZERO, |sum, size| sum.saturating_add(*size));
+        let allocations = allocate_pro_rata(remaining.min(level_size), &sizes);
+
+        for (&j, &allocation) in level.iter().zip(&allocations) {
+            if allocation == U256::ZERO
+                || allocation < orders[i].min_fill_amount
+                || allocation < orders[j].min_fill_amount
+            {
+                continue;
+            }
+            if let Some(mut result) = settle_pair(config, &orders[i], &orders[j], allocation) {
+                if breaker_blocks(config, &orders[i], &result) {
+                    summary.breaker_trips += 1;
+                    record_skips(summary, MatchBlock::CircuitBreaker, 1);
+                    continue;
+                }
+                // Order i is only dust-cleared once the whole level is done,
+                // so the level's allocations always fit in it
+                orders[i].amount -= allocation;
+                orders[j].amount = remaining_after_fill(&orders[j], allocation);
+                on_fill(&mut result);
+
+                summary.matches_found += 1;
+                fills.push((i, j, result));
+            }
+        }
+
+        if orders[i].amount < orders[i].min_fill_amount {
+            orders[i].amount = U256::ZERO;
+        }
+    }
+}
+
+/// Whether the circuit breaker stops `result`, a fill in `order`'s market
+pub fn breaker_blocks(config: &MatchConfig, order: &Order, result: &MatchResult) -> bool {
+    let (base, quote) = order.base_quote();
+    config.breaker_trips(base, quote, result.execution_price)
+}
+
+/// Fill one incoming order against a book, best price first
+///
+/// Resting orders on the other side of `taker`'s market are walked in
+/// `priority_cmp` order and each fills at its own limit price; in pro-rata
+/// mode each price level's displayed and then hidden size is instead shared
+/// with `allocate_pro_rata`. The walk stops at the first order priced beyond
+/// `worst_price` or once the taker is filled; resting orders whose minimum
+/// fill their allocation can't meet are passed over, as are fills the
+/// circuit breaker stops (counted in `summary`). `taker.amount` is the size
+/// to fill and `taker.id` is reported as its side of each fill.
+///
+/// Fills are applied to `orders` and returned as (resting_index, result),
+/// together with the amount left unfilled.
+pub fn fill_incoming<F>(
+    config: &MatchConfig,
+    taker: &Order,
+    worst_price: U256,
+    orders: &mut [Order],
+    summary: &mut MatchSummary,
+    mut on_fill: F,
+) -> (Vec<(usize, MatchResult)>, U256)
+where
+    F: FnMut(&mut MatchResult),
+{
+    let mut candidates = contra_orders(config, taker, orders);
+
+    candidates.sort_by(|&a, &b| priority_cmp(taker.is_buy, &orders[a], &orders[b]));
+
+    let mut fills: Vec<(usize, MatchResult)> = Vec::new();
+    let mut remaining = taker.amount;
+
+    for range in price_levels(orders, &candidates) {
+        let level = &candidates[range];
+        if remaining == U256::ZERO {
+            break;
+        }
+
+        let level_price = orders[level[0]].limit_price;
+        let beyond_bound = if taker.is_buy {
+            level_price > worst_price
+        } else {
+            level_price < worst_price
+        };
+        if beyond_bound {
+            break;
+        }
+
+        let allocations = if config.pro_rata {
+            let sizes: Vec<U256> = level.iter().map(|&index| orders[index].amount).collect();
+            let level_size = sizes
+                .iter()
+                .fold(U256::ZERO, |sum, size| sum.saturating_add(*size));
+            allocate_pro_rata(remaining.min(level_size), &sizes)
+        } else {
+            Vec::new()
+        };
+
+        // Every fill at this level prints the same price
+        let (base, quote) = orders[level[0]].base_quote();
+        if config.breaker_trips(base, quote, level_price) {
+            summary.breaker_trips += index_to_u64(level.len());
+            record_skips(
+                summary,
+                MatchBlock::CircuitBreaker,
+                index_to_u64(level.len()),
+            );
+            continue;
+        }
+
+        for (k, &index) in level.iter().enumerate() {
+            if let Some(mut result) = fill_resting(
+                config,
+                taker,
+                &mut orders[index],
+                &mut remaining,
+                allocations.get(k).copied(),
+            ) {
+                on_fill(&mut result);
+                fills.push((index, result));
+            }
+        }
+    }
+
+    (fills, remaining)
+}
+
+/// Indices of the resting orders on the other side of `taker`'s market that
+/// it may trade with, in book order
+fn contra_orders(config: &MatchConfig, taker: &Order, orders: &[Order]) -> Vec<usize> {
+    (0..orders.len())
+        .filter(|&i| {
+            let resting = &orders[i];
+            resting.amount != U256::ZERO
+                && !config.dormant(resting)
+                && resting.is_buy != taker.is_buy
+                && resting.token_in == taker.token_out
+                && resting.token_out == taker.token_in
+                && (resting.trader != taker.trader || config.allow_self_trade)
+                && !config.counterparties_blocked(resting.trader, taker.trader)
+                && resting.internal_balance == taker.internal_balance
+                && !config.outside_collar(resting, resting.limit_price)
+        })
+        .collect()
+}
+
+/// Quote notional `fill_incoming` could reach for `taker`: every resting
+/// order it may trade with priced within `worst_price`, valued at its own
+/// limit price
+///
+/// Minimum fills are ignored, so this is an upper bound on what fills.
+pub fn reachable_liquidity(
+    config: &MatchConfig,
+    taker: &Order,
+    worst_price: U256,
+    orders: &[Order],
+) -> U256 {
+    contra_orders(config, taker, orders)
+        .into_iter()
+        .map(|i| &orders[i])
+        .filter(|resting| {
+            if taker.is_buy {
+                resting.limit_price <= worst_price
+            } else {
+                resting.limit_price >= worst_price
+            }
+        })
+        .fold(U256::ZERO, |sum, resting| {
+            let notional = quote_owed(config, resting, resting.amount, resting.limit_price)
+                .unwrap_or(U256::MAX);
+            sum.saturating_add(notional)
+        })
+}
+
+/// Canonical priority of two resting orders for an incoming order on the
+/// other side (`incoming_buys` = the incoming order is a buy)
+///
+/// Better price first (cheaper sells, richer buys); at equal price displayed
+/// orders go before fully hidden ones regardless of age; within each
+/// visibility class the older order goes first.
+pub fn priority_cmp(incoming_buys: bool, a: &Order, b: &Order) -> Ordering {
+    let by_price = if incoming_buys {
+        a.limit_price.cmp(&b.limit_price)
+    } else {
+        b.limit_price.cmp(&a.limit_price)
+    };
+    by_price
+        .then(a.fully_hidden.cmp(&b.fully_hidden))
+        .then(time_priority_cmp(a, b))
+}
+
+/// Which of two stored orders the engine serves first
+///
+/// Orders on the same side rank as `priority_cmp` ranks resting orders for
+/// an incoming order from the other side; orders on opposite sides rank by
+/// `time_priority_cmp`, which decides the resting side of a book scan.
+pub fn serve_order(a: &Order, b: &Order) -> Ordering {
+    if a.is_buy == b.is_buy {
+        priority_cmp(!a.is_buy, a, b)
+    } else {
+        time_priority_cmp(a, b)
+    }
+}
+
+/// Whether two orders could fill against each other now, as (ok, reason,
+/// fill_amount, fill_price), or None if the fill can't be priced
+///
+/// Makes the calls a price-time book scan makes for the pair: iceberg
+/// reserves are hidden, the older order rests, `match_block` gives the
+/// reason (a `MatchBlock` code, 0 when ok), then `execute_single_match`
+/// prices the fill and the circuit breaker has the last word.
+pub fn pair_eligibility(
+    config: &MatchConfig,
+    a: &Order,
+    b: &Order,
+) -> Option<(bool, u8, U256, U256)> {
+    let mut pair = [a.clone(), b.clone()];
+    canonicalize(&mut pair);
+    hide_reserves(&mut pair);
+    let [resting, incoming] = &pair;
+
+    if let Some(block) = match_block(config, resting, incoming) {
+        return Some((false, block as u8, U256::ZERO, U256::ZERO));
+    }
+    let result = execute_single_match(config, resting, incoming)?;
+    if breaker_blocks(config, resting, &result) {
+        return Some((
+            false,
+            MatchBlock::CircuitBreaker as u8,
+            U256::ZERO,
+            U256::ZERO,
+        ));
+    }
+    Some((true, 0, result.amount, result.execution_price))
+}
+
+/// Fingerprint bit set while an order has size left
+const FINGERPRINT_LIVE: U256 = U256::from_limbs([0, 0, 0, 1 << 63]);
+/// Fingerprint bit set for buy orders
+const FINGERPRINT_BUY: U256 = U256::from_limbs([0, 0, 0, 1 << 62]);
+/// Largest price a fingerprint holds exactly; higher prices saturate to it
+const FINGERPRINT_PRICE_MAX: U256 = U256::from_limbs([u64::MAX, u64::MAX, u64::MAX, (1 << 62) - 1]);
+
+/// Pack what the matching pre-filter needs into one storage word: a live
+/// bit, a side bit and the limit price (saturating) below them
+///
+/// Orders with a cancel pending count as dead.
+///
+/// Books are already split by pair, so no pair bits are needed. Saturation
+/// only merges prices no real market uses, and merged prices are treated
+/// as crossing, so the pre-filter stays conservative.
+pub fn fingerprint(order: &Order) -> U256 {
+    let mut word = order.limit_price.min(FINGERPRINT_PRICE_MAX);
+    if order.amount != U256::ZERO && !order.cancel_pending {
+        word |= FINGERPRINT_LIVE;
+    }
+    if order.is_buy {
+        word |= FINGERPRINT_BUY;
+    }
+    word
+}
+
+/// Positions of the orders a matching pass could possibly fill, judged from
+/// their fingerprints alone
+///
+/// Dead orders are dropped, as are buys below every live sell and sells
+/// above every live buy: `can_match` needs a crossing price, so none of
+/// them could fill, and leaving them out changes no fill of a book scan.
+pub fn crossing_candidates(fingerprints: &[U256]) -> Vec<usize> {
+    let price = |word: U256| word & FINGERPRINT_PRICE_MAX;
+    let live = |word: &U256| *word & FINGERPRINT_LIVE != U256::ZERO;
+    let buy = |word: &U256| *word & FINGERPRINT_BUY != U256::ZERO;
+
+    let best_buy = fingerprints
+        .iter()
+        .filter(|w| live(w) && buy(w))
+        .map(|&w| price(w))
+        .max();
+    let best_sell = fingerprints
+        .iter()
+        .filter(|w| live(w) && !buy(w))
+        .map(|&w| price(w))
+        .min();
+    let (best_buy, best_sell) = match (best_buy, best_sell) {
+        (Some(best_buy), Some(best_sell)) => (best_buy, best_sell),
+        _ => return Vec::new(),
+    };
+
+    fingerprints
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| {
+            live(w)
+                && if buy(w) {
+                    price(**w) >= best_sell
+                } else {
+                    price(**w) <= best_buy
+                }
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Order two orders oldest first, breaking timestamp ties by submission
+/// sequence and then by id
+///
+/// Ids are never reused, so this is a total order over any book.
+pub fn time_priority_cmp(a: &Order, b: &Order) -> Ordering {
+    a.timestamp
+        .cmp(&b.timestamp)
+        .then(a.sequence.cmp(&b.sequence))
+        .then(a.id.cmp(&b.id))
+}
+
+/// Sort a book into the canonical order scans expect
+///
+/// Storage slots stop reflecting age once compaction swap-removes orders,
+/// so every caller sorts with this before scanning; any permutation of the
+/// same orders then yields the same fills.
+pub fn canonicalize(orders: &mut [Order]) {
+    orders.sort_by(time_priority_cmp);
+}
+
+/// Split priority-sorted candidate indices into runs sharing one limit price
+/// and visibility class, so displayed size at a price is exhausted before
+/// hidden size there is touched
+fn price_levels(orders: &[Order], candidates: &[usize]) -> Vec<Range<usize>> {
+    let mut levels: Vec<Range<usize>> = Vec::new();
+    let mut start = 0;
+    for end in 1..=candidates.len() {
+        let level_ends = end == candidates.len() || {
+            let (next, first) = (&orders[candidates[end]], &orders[candidates[start]]);
+            next.limit_price != first.limit_price || next.fully_hidden != first.fully_hidden
+        };
+        if level_ends {
+            levels.push(start..end);
+            start = end;
+        }
+    }
+    levels
+}
+
+/// Fill one resting order from an incoming order's `remaining` size
+///
+/// Takes `allocation` when pro-rata sharing set one, otherwise as much as
+/// the resting order holds. Returns None when the fill would be empty or
+/// below the resting order's minimum fill.
+fn fill_resting(
+    config: &MatchConfig,
+    taker: &Order,
+    resting: &mut Order,
+    remaining: &mut U256,
+    allocation: Option<U256>,
+) -> Option<MatchResult> {
+    let matched_amount = allocation
+        .unwrap_or(resting.amount)
+        .min(*remaining)
+        .min(resting.amount);
+    if matched_amount == U256::ZERO || matched_amount < resting.min_fill_amount {
+        return None;
+    }
+
+    let (buy_order_id, sell_order_id) = if taker.is_buy {
+        (taker.id, resting.id)
+    } else {
+        (resting.id, taker.id)
+    };
+    let result = MatchResult {
+        buy_order_id,
+        sell_order_id,
+        // No taker limit to average with, so the resting price stands
+        execution_price: resting.limit_price,
+        amount: matched_amount,
+        quote_amount: quote_owed(config, resting, matched_amount, resting.limit_price)?,
+        fee: fill_fee(config, matched_amount)?,
+        gas_used: U256::ZERO,
+        flagged: false,
+    };
+
+    resting.amount = remaining_after_fill(resting, matched_amount);
+    *remaining -= matched_amount;
+    Some(result)
+}
+
+/// Settle a closed ring of orders across several markets
+///
+/// Leg `k` gives its token_in to leg `k + 1` (wrapping), so each leg's
+/// token_in must be the next leg's token_out. Every leg after the first gives
+/// the most its limit allows for what it receives, and the ring is sized to
+/// the largest flow no leg's remaining amount caps; the first leg keeps any
+/// surplus as price improvement. Returns one result per leg, paired with the
+/// leg it gives to, and applies the fills to `legs`. Returns None if the
+/// tokens don't close, a limit or minimum fill would be violated, a leg's
+/// price would trip the circuit breaker or its collar, or two adjacent legs may not trade
+/// with each other (including a ledger-backed leg next to one that isn't).
+pub fn settle_ring(config: &MatchConfig, legs: &mut [Order]) -> Option<Vec<MatchResult>> {
+    let n = legs.len();
+    if n < 2 {
+        return None;
+    }
+
+    for k in 0..n {
+        let (leg, next) = (&legs[k], &legs[(k + 1) % n]);
+        if leg.token_in != next.token_out || leg.amount == U256::ZERO {
+            return None;
+        }
+        if config.dormant(leg) {
+            return None;
+        }
+        if leg.limit_price == U256::ZERO {
+            return None;
+        }
+        if leg.trader == next.trader && !config.allow_self_trade {
+            return None;
+        }
+        if config.counterparties_blocked(leg.trader, next.trader) {
+            return None;
+        }
+        if leg.internal_balance != next.internal_balance {
+            return None;
+        }
+    }
+
+    // Each leg's limit in raw units, as quote units per base unit
+    let mut limits: Vec<(U256, U256)> = Vec::with_capacity(n);
+    for leg in legs.iter() {
+        let (base, quote) = leg.base_quote();
+        limits.push(unit_rate(config, base, quote, leg.limit_price)?);
+    }
+
+    // Most a leg gives per unit received, as a (num, den) rate: a buy pays up
+    // to its limit in quote per base, a sell gives the inverse in base per quote
+    let rate = |k: usize| {
+        let (num, den) = limits[k];
+        if legs[k].is_buy {
+            (num, den)
+        } else {
+            (den, num)
+        }
+    };
+
+    // Cumulative rate from leg 0's flow to leg k's, so given[k] = given[0] * growth[k]
+    let mut growth: Vec<(U256, U256)> = Vec::with_capacity(n);
+    growth.push((U256::from(1), U256::from(1)));
+    for k in 1..n {
+        let (num, den) = growth[k - 1];
+        let (rate_num, rate_den) = rate(k);
+        growth.push(reduce(
+            num.checked_mul(rate_num)?,
+            den.checked_mul(rate_den)?,
+        ));
+    }
+
+    // Size leg 0's flow so no leg exceeds its remaining amount: sells cap
+    // what they give, buys cap what they receive (the previous leg's flow)
+    let mut first_given = U256::MAX;
+    for k in 0..n {
+        let (num, den) = if legs[k].is_buy {
+            growth[(k + n - 1) % n]
+        } else {
+            growth[k]
+        };
+        first_given = first_given.min(legs[k].amount.checked_mul(den)? / num);
+    }
+
+    let mut given: Vec<U256> = Vec::with_capacity(n);
+    given.push(first_given);
+    for k in 1..n {
+        let (rate_num, rate_den) = rate(k);
+        given.push(given[k - 1].checked_mul(rate_num)? / rate_den);
+    }
+
+    // Check every leg against its own limits before touching anything
+    let mut fills: Vec<(U256, U256, U256, U256)> = Vec::with_capacity(n);
+    for k in 0..n {
+        let leg = &legs[k];
+        let gives = given[k];
+        let receives = given[(k + n - 1) % n];
+        if gives == U256::ZERO || receives == U256::ZERO {
+            return None;
+        }
+
+        // (base filled, quote exchanged) from this leg's point of view
+        let (base, quote) = if leg.is_buy {
+            (receives, gives)
+        } else {
+            (gives, receives)
+        };
+        let (limit_num, limit_den) = limits[k];
+        let (paid, allowed) = (quote.checked_mul(limit_den)?, base.checked_mul(limit_num)?);
+        let within_limit = if leg.is_buy {
+            paid <= allowed
+        } else {
+            paid >= allowed
+        };
+        if !within_limit || base > leg.amount || base < leg.min_fill_amount {
+            return None;
+        }
+
+        let (base_token, quote_token) = leg.base_quote();
+        let execution_price = scaled_price(config, base_token, quote_token, base, quote)?;
+        if config.breaker_trips(base_token, quote_token, execution_price)
+            || config.outside_collar(leg, execution_price)
+        {
+            return None;
+        }
+        fills.push((base, quote, execution_price, fill_fee(config, base)?));
+    }
+
+    let mut results: Vec<MatchResult> = Vec::with_capacity(n);
+    for k in 0..n {
+        let (base, quote, execution_price, fee) = fills[k];
+        let next_id = legs[(k + 1) % n].id;
+        let (buy_order_id, sell_order_id) = if legs[k].is_buy {
+            (legs[k].id, next_id)
+        } else {
+            (next_id, legs[k].id)
+        };
+        results.push(MatchResult {
+            buy_order_id,
+            sell_order_id,
+            execution_price,
+            amount: base,
+            quote_amount: quote,
+            fee,
+            gas_used: U256::ZERO,
+            flagged: false,
+        });
+        legs[k].amount = remaining_after_fill(&legs[k], base);
+    }
+
+    Some(results)
+}
+
+/// Search three markets for rings of one opted-in order from each, and
+/// settle every ring found
+///
+/// Leg `k` of a candidate ring is taken from `books[k]`, so the caller picks
+/// the direction tokens flow by how it orders the books; triples whose
+/// tokens don't close in that direction are skipped without counting as a
+/// comparison. Each book must be in time priority, and rows are tried oldest
+/// first, so a leg left with a residual after one ring keeps looking for the
+/// next. Every ring is settled with `settle_ring`, with the same limit,
+/// minimum fill, self-trade and circuit breaker checks. Returns the rows of
+/// each ring's legs with its results, and applies the fills to `books`;
+/// `on_ring` sees the first leg's result of each ring, which carries the
+/// ring's metrics.
+pub fn scan_triangles<F>(
+    config: &MatchConfig,
+    books: [&mut [Order]; 3],
+    summary: &mut MatchSummary,
+    mut on_ring: F,
+) -> Vec<([usize; 3], Vec<MatchResult>)>
+where
+    F: FnMut(&mut MatchResult),
+{
+    let [first, second, third] = books;
+    let mut rings: Vec<([usize; 3], Vec<MatchResult>)> = Vec::new();
+    let candidate =
+        |order: &Order| order.amount != U256::ZERO && order.allow_ring && !config.dormant(order);
+
+    for (i, a) in first.iter_mut().enumerate() {
+        for (j, b) in second.iter_mut().enumerate() {
+            if !candidate(a) {
+                break;
+            }
+            if !candidate(b) || a.token_in != b.token_out {
+                continue;
+            }
+            for (k, c) in third.iter_mut().enumerate() {
+                if !candidate(a) || !candidate(b) {
+                    break;
+                }
+                let closes = b.token_in == c.token_out && c.token_in == a.token_out;
+                if !closes || !candidate(c) {
+                    continue;
+                }
+
+                summary.comparisons += 1;
+                let mut legs = alloc::vec![a.clone(), b.clone(), c.clone()];
+                if let Some(mut results) = settle_ring(config, &mut legs) {
+                    a.amount = legs[0].amount;
+                    b.amount = legs[1].amount;
+                    c.amount = legs[2].amount;
+                    on_ring(&mut results[0]);
+
+                    summary.matches_found += index_to_u64(results.len());
+                    rings.push(([i, j, k], results));
+                }
+            }
+        }
+    }
+
+    rings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use stylus_sdk::alloy_primitives::U512;
+
+    const BASE: Address = Address::repeat_byte(0xba);
+    const QUOTE: Address = Address::repeat_byte(0x9e);
+
+    /// A plain limit order in the BASE/QUOTE market
+    fn order(id: u64, is_buy: bool, amount: u64, limit_price: U256) -> Order {
+        let (token_in, token_out) = if is_buy { (QUOTE, BASE) } else { (BASE, QUOTE) };
+        Order {
+            id,
+            trader: Address::from_word(U256::from(id).into()),
+            token_in,
+            token_out,
+            amount: U256::from(amount),
+            limit_price,
+            is_buy,
+            timestamp: id,
+            sequence: id,
+            ..Default::default()
+        }
+    }
+
+    /// `price` whole quote tokens per base token
+    fn whole(price: u64) -> U256 {
+        PRICE_SCALE * U256::from(price)
+    }
+
+    /// A scan's fills as (buy id, sell id, amount)
+    fn filled(fills: &[(usize, usize, MatchResult)]) -> Vec<(u64, u64, U256)> {
+        fills
+            .iter()
+            .map(|(_, _, result)| (result.buy_order_id, result.sell_order_id, result.amount))
+            .collect()
+    }
+
+    #[test]
+    fn fees_accrue_on_every_fill_of_a_pass() {
+        let config = MatchConfig {
+            fee_bps: 30,
+            ..Default::default()
+        };
+        let mut orders = vec![
+            order(1, false, 1_000_000, PRICE_SCALE),
+            order(2, false, 500_000, PRICE_SCALE),
+            order(3, true, 1_200_000, PRICE_SCALE),
+        ];
+        let mut summary = MatchSummary::default();
+        let fills = scan_orders(&config, &mut orders, &mut summary, |_| {});
+
+        let fees: Vec<U256> = fills.iter().map(|(_, _, result)| result.fee).collect();
+        assert_eq!(fees, vec![U256::from(3_000), U256::from(600)]);
+        let filled = fills
+            .iter()
+            .fold(U256::ZERO, |sum, (_, _, result)| sum + result.amount);
+        assert_eq!(filled, U256::from(1_200_000));
+    }
+
+    #[test]
+    fn fill_fee_rejects_overflow() {
+        let config = MatchConfig {
+            fee_bps: 30,
+            ..Default::default()
+        };
+        assert_eq!(fill_fee(&config, U256::MAX), None);
+        assert_eq!(
+            fill_fee(&config, U256::MAX / U256::from(30)),
+            Some(U256::MAX / U256::from(30) * U256::from(30) / U256::from(BPS_DENOMINATOR))
+        );
+        let free = MatchConfig::default();
+        assert_eq!(fill_fee(&free, U256::MAX), Some(U256::ZERO));
+    }
+
+    /// xorshift64* words for the property tests
+    fn words(mut seed: u64) -> impl Iterator<Item = u64> {
+        core::iter::from_fn(move || {
+            seed ^= seed >> 12;
+            seed ^= seed << 25;
+            seed ^= seed >> 27;
+            Some(seed.wrapping_mul(0x2545_f491_4f6c_dd1d))
+        })
+    }
+
+    /// A price spread over the whole U256 range, biased toward the top
+    fn random_price(words: &mut impl Iterator<Item = u64>) -> U256 {
+        let limbs = [(); 4].map(|_| words.next().unwrap());
+        match limbs[0] % 4 {
+            0 => U256::MAX - U256::from(limbs[1] % 1_000),
+            1 => U256::from(limbs[1]),
+            _ => U256::from_limbs(limbs),
+        }
+    }
+
+    fn midpoint(buy: U256, sell: U256) -> U256 {
+        midpoint_price(&order(1, true, 1, buy), &order(2, false, 1, sell))
+    }
+
+    #[test]
+    fn midpoint_handles_prices_at_the_top_of_the_range() {
+        assert_eq!(midpoint(U256::MAX, U256::MAX), U256::MAX);
+        assert_eq!(
+            midpoint(U256::MAX, U256::MAX - U256::from(1)),
+            U256::MAX - U256::from(1)
+        );
+        assert_eq!(
+            midpoint(U256::MAX, U256::MAX - U256::from(2)),
+            U256::MAX - U256::from(1)
+        );
+        assert_eq!(midpoint(U256::MAX, U256::ZERO), U256::MAX / U256::from(2));
+        assert_eq!(
+            midpoint(U256::MAX - U256::from(1), U256::MAX - U256::from(1)),
+            U256::MAX - U256::from(1)
+        );
+    }
+
+    #[test]
+    fn midpoint_is_the_floored_mean_within_the_limits() {
+        let mut words = words(0x5eed_1234);
+        for _ in 0..10_000 {
+            let (a, b) = (random_price(&mut words), random_price(&mut words));
+            let (sell, buy) = (a.min(b), a.max(b));
+            let price = midpoint(buy, sell);
+            assert!(sell <= price && price <= buy);
+            let mean = (U512::from(buy) + U512::from(sell)) / U512::from(2);
+            assert_eq!(U512::from(price), mean);
+            // Limits that don't cross leave the sell price standing
+            assert_eq!(midpoint(sell, buy), buy);
+        }
+    }
+
+    #[test]
+    fn modify_requeues_only_on_growth_or_a_more_aggressive_price() {
+        let price = U256::from(100);
+        let (up, down) = (U256::from(101), U256::from(99));
+        let buy = order(1, true, 1_000, price);
+        let sell = order(2, false, 1_000, price);
+        let (less, same, more) = (U256::from(999), U256::from(1_000), U256::from(1_001));
+
+        // Nothing changed, or only the size shrank
+        assert!(!modify_requeues(&buy, same, price));
+        assert!(!modify_requeues(&buy, less, price));
+        assert!(!modify_requeues(&sell, less, price));
+
+        // Growing the size
+        assert!(modify_requeues(&buy, more, price));
+        assert!(modify_requeues(&sell, more, price));
+
+        // A buy bidding higher, or a sell asking lower
+        assert!(modify_requeues(&buy, same, up));
+        assert!(modify_requeues(&sell, same, down));
+
+        // A buy bidding lower, or a sell asking higher
+        assert!(!modify_requeues(&buy, less, down));
+        assert!(!modify_requeues(&sell, less, up));
+
+        // Shrinking doesn't buy a better price its place
+        assert!(modify_requeues(&buy, less, up));
+        assert!(modify_requeues(&sell, less, down));
+    }
+
+    #[test]
+    fn a_scheduled_order_requeued_early_stays_behind_its_activation() {
+        let price = U256::from(100);
+        let mut scheduled = order(1, false, 1_000, price);
+        scheduled.activation_time = 500;
+        scheduled.timestamp = scheduled.priority_time(10);
+        let placed_before_activation = Order {
+            timestamp: 400,
+            ..order(2, false, 1_000, price)
+        };
+
+        // Growing it at 200, before it activates, re-stamps it
+        assert!(modify_requeues(&scheduled, U256::from(2_000), price));
+        scheduled.timestamp = scheduled.priority_time(200);
+        assert_eq!(scheduled.timestamp, 500);
+        assert_eq!(
+            time_priority_cmp(&placed_before_activation, &scheduled),
+            Ordering::Less
+        );
+
+        // Once active it re-stamps with the time of the change
+        assert_eq!(scheduled.priority_time(600), 600);
+    }
+
+    fn amounts(raw: &[u64]) -> Vec<U256> {
+        raw.iter().map(|&amount| U256::from(amount)).collect()
+    }
+
+    #[test]
+    fn pro_rata_remainder_goes_to_the_oldest_orders() {
+        assert_eq!(
+            allocate_pro_rata(U256::from(7), &amounts(&[3, 3, 3])),
+            amounts(&[3, 2, 2])
+        );
+        // The oldest order is already full, so the next one takes the rest
+        assert_eq!(
+            allocate_pro_rata(U256::from(8), &amounts(&[1, 3, 6])),
+            amounts(&[1, 3, 4])
+        );
+        assert_eq!(
+            allocate_pro_rata(U256::from(5), &amounts(&[1, 1, 8])),
+            amounts(&[1, 0, 4])
+        );
+        assert_eq!(
+            allocate_pro_rata(U256::from(5), &amounts(&[0, 0])),
+            amounts(&[0, 0])
+        );
+        // Shares too large to compute fall back to filling oldest first
+        let half = U256::MAX / U256::from(2);
+        assert_eq!(
+            allocate_pro_rata(U256::MAX - U256::from(2), &[half, half]),
+            vec![half, half - U256::from(1)]
+        );
+    }
+
+    #[test]
+    fn pro_rata_allocations_sum_to_the_fill() {
+        let mut words = words(0x9a7a);
+        for _ in 0..2_000 {
+            let count = 1 + crate::index_to_usize(words.next().unwrap() % 8);
+            let sizes: Vec<U256> = (0..count)
+                .map(|_| U256::from(words.next().unwrap() % 1_000))
+                .collect();
+            let total = sizes.iter().fold(U256::ZERO, |sum, size| sum + *size);
+            let amount = U256::from(words.next().unwrap()) % (total + U256::from(1));
+
+            let shares = allocate_pro_rata(amount, &sizes);
+            let allocated = shares.iter().fold(U256::ZERO, |sum, share| sum + *share);
+            assert_eq!(allocated, amount);
+            for (k, (share, size)) in shares.iter().zip(&sizes).enumerate() {
+                assert!(share <= size);
+                let floor = amount * *size / total;
+                assert!(*share >= floor);
+                // Only an order behind full ones may get a rounding extra
+                if *share > floor {
+                    assert!(shares[..k].iter().zip(&sizes).all(|(s, z)| s == z));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn min_fill_skips_small_counter_orders_and_clears_dust() {
+        let mut resting = order(1, false, 1_000, whole(10));
+        resting.min_fill_amount = U256::from(100);
+        let mut orders = vec![
+            resting,
+            order(2, true, 50, whole(10)),
+            order(3, true, 150, whole(10)),
+            order(4, true, 820, whole(10)),
+        ];
+        let mut summary = MatchSummary::default();
+        let fills = scan_orders(&MatchConfig::default(), &mut orders, &mut summary, |_| {});
+
+        // The 50-unit buy is below the sell's minimum; after 150 and 820 the
+        // 30 left can never fill again, so it is cleared
+        assert_eq!(
+            filled(&fills),
+            vec![(3, 1, U256::from(150)), (4, 1, U256::from(820))]
+        );
+        assert_eq!(orders[0].amount, U256::ZERO);
+        assert_eq!(orders[1].amount, U256::from(50));
+        assert_eq!(summary.skipped[MatchBlock::BelowMinFill as usize], 1);
+    }
+
+    #[test]
+    fn self_trades_are_skipped_for_the_next_candidate() {
+        let trader = Address::repeat_byte(0x5e);
+        let own_buy = Order {
+            trader,
+            ..order(1, true, 100, whole(10))
+        };
+        let own_sell = Order {
+            trader,
+            ..order(2, false, 100, whole(10))
+        };
+        let book = vec![own_buy, own_sell, order(3, false, 100, whole(10))];
+
+        let mut orders = book.clone();
+        let mut summary = MatchSummary::default();
+        let fills = scan_orders(&MatchConfig::default(), &mut orders, &mut summary, |_| {});
+        assert_eq!(filled(&fills), vec![(1, 3, U256::from(100))]);
+        assert_eq!(orders[1].amount, U256::from(100));
+        assert_eq!(summary.skipped[MatchBlock::SelfTrade as usize], 1);
+
+        // Unless the owner allows them
+        let config = MatchConfig {
+            allow_self_trade: true,
+            ..Default::default()
+        };
+        let mut orders = book;
+        let fills = scan_orders(&config, &mut orders, &mut MatchSummary::default(), |_| {});
+        assert_eq!(filled(&fills), vec![(1, 2, U256::from(100))]);
+    }
+
+    /// A market order for `amount` in the BASE/QUOTE market
+    fn market(is_buy: bool, amount: u64) -> Order {
+        Order {
+            id: crate::MARKET_ORDER_ID,
+            ..order(99, is_buy, amount, U256::ZERO)
+        }
+    }
+
+    /// Incoming fills as (resting id, amount, price, quote_amount)
+    fn taken(fills: &[(usize, MatchResult)]) -> Vec<(u64, U256, U256, U256)> {
+        fills
+            .iter()
+            .map(|(_, result)| {
+                let resting = if result.buy_order_id == crate::MARKET_ORDER_ID {
+                    result.sell_order_id
+                } else {
+                    result.buy_order_id
+                };
+                (
+                    resting,
+                    result.amount,
+                    result.execution_price,
+                    result.quote_amount,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn market_buy_sweeps_sells_at_their_own_prices() {
+        // Stored out of price order
+        let book = vec![
+            order(1, false, 100, whole(12)),
+            order(2, false, 100, whole(10)),
+            order(3, false, 100, whole(11)),
+        ];
+        let config = MatchConfig::default();
+
+        let mut orders = book.clone();
+        let (fills, left) = fill_incoming(
+            &config,
+            &market(true, 250),
+            whole(12),
+            &mut orders,
+            &mut MatchSummary::default(),
+            |_| {},
+        );
+        let units = |amount: u64| U256::from(amount);
+        assert_eq!(
+            taken(&fills),
+            vec![
+                (2, units(100), whole(10), units(1_000)),
+                (3, units(100), whole(11), units(1_100)),
+                (1, units(50), whole(12), units(600)),
+            ]
+        );
+        assert_eq!(left, U256::ZERO);
+        assert_eq!(orders[0].amount, units(50));
+
+        // A bound of 11 stops the sweep before the third level
+        let mut orders = book;
+        let (fills, left) = fill_incoming(
+            &config,
+            &market(true, 250),
+            whole(11),
+            &mut orders,
+            &mut MatchSummary::default(),
+            |_| {},
+        );
+        assert_eq!(
+            taken(&fills),
+            vec![
+                (2, units(100), whole(10), units(1_000)),
+                (3, units(100), whole(11), units(1_100)),
+            ]
+        );
+        assert_eq!(left, units(50));
+        assert_eq!(orders[0].amount, units(100));
+    }
+
+    const RING_A: Address = Address::repeat_byte(0xa1);
+    const RING_B: Address = Address::repeat_byte(0xb2);
+    const RING_C: Address = Address::repeat_byte(0xc3);
+
+    /// A ring-enabled sell of `token_in` for at least `price` of `token_out`
+    /// per unit (in PRICE_SCALE units)
+    fn leg(id: u64, token_in: Address, token_out: Address, amount: u64, price: U256) -> Order {
+        Order {
+            token_in,
+            token_out,
+            allow_ring: true,
+            ..order(id, false, amount, price)
+        }
+    }
+
+    /// A sells for B at 2, C for A at 1/4 and B for C at 2: the rates
+    /// multiply to exactly 1, so the ring clears with no surplus
+    fn ring() -> Vec<Order> {
+        vec![
+            leg(1, RING_A, RING_B, 100, whole(2)),
+            leg(2, RING_C, RING_A, 1_000, PRICE_SCALE / U256::from(4)),
+            leg(3, RING_B, RING_C, 500, whole(2)),
+        ]
+    }
+
+    /// Ring results as (buy id, sell id, amount, quote_amount)
+    fn legs_filled(results: &[MatchResult]) -> Vec<(u64, u64, U256, U256)> {
+        results
+            .iter()
+            .map(|r| (r.buy_order_id, r.sell_order_id, r.amount, r.quote_amount))
+            .collect()
+    }
+
+    #[test]
+    fn a_valid_three_leg_ring_settles_every_leg() {
+        let mut legs = ring();
+        let results = settle_ring(&MatchConfig::default(), &mut legs).unwrap();
+
+        // Leg 1's 100 A caps the ring: 100 A buys 400 C, which buys 200 B,
+        // which buys back the 100 A
+        let units = |amount: u64| U256::from(amount);
+        assert_eq!(
+            legs_filled(&results),
+            vec![
+                (2, 1, units(100), units(200)),
+                (3, 2, units(400), units(100)),
+                (1, 3, units(200), units(400)),
+            ]
+        );
+        let prices: Vec<U256> = results.iter().map(|r| r.execution_price).collect();
+        assert_eq!(
+            prices,
+            vec![whole(2), PRICE_SCALE / U256::from(4), whole(2)]
+        );
+        let left: Vec<U256> = legs.iter().map(|leg| leg.amount).collect();
+        assert_eq!(left, vec![units(0), units(600), units(300)]);
+    }
+
+    #[test]
+    fn invalid_rings_are_rejected_untouched() {
+        let config = MatchConfig::default();
+        let rejects = |legs: &mut Vec<Order>| {
+            let before: Vec<U256> = legs.iter().map(|leg| leg.amount).collect();
+            let settled = settle_ring(&config, legs);
+            let after: Vec<U256> = legs.iter().map(|leg| leg.amount).collect();
+            settled.is_none() && before == after
+        };
+
+        // The last leg pays out a token nobody in the ring wants
+        let mut legs = ring();
+        legs[2].token_in = Address::repeat_byte(0xd4);
+        assert!(rejects(&mut legs));
+
+        // The same trader on two legs
+        let mut legs = ring();
+        legs[1].trader = legs[0].trader;
+        assert!(rejects(&mut legs));
+
+        // A leg that would fill 400 but takes no less than 500
+        let mut legs = ring();
+        legs[1].min_fill_amount = U256::from(500);
+        assert!(rejects(&mut legs));
+
+        // A limit the other legs can't meet
+        let mut legs = ring();
+        legs[2].limit_price = whole(3);
+        assert!(rejects(&mut legs));
+
+        // Too few legs to form a ring
+        let mut legs = ring();
+        legs.truncate(1);
+        assert!(rejects(&mut legs));
+    }
+
+    #[test]
+    fn stop_sells_activate_only_once_a_trade_prints_through_the_trigger() {
+        let stop = |id: u64, trigger: u64| Order {
+            trigger_price: whole(trigger),
+            trigger_direction: crate::TriggerDirection::AtOrBelow as u8,
+            ..order(id, false, 100, whole(90))
+        };
+        let (mut activates, never) = (stop(1, 95), stop(2, 80));
+        let bid = order(3, true, 100, whole(100));
+        let config = MatchConfig::default();
+
+        // No trade yet, then prints above the trigger
+        for last in [U256::ZERO, whole(100), whole(96)] {
+            assert!(!activates.trigger_reached(last));
+            assert!(!never.trigger_reached(last));
+        }
+        assert_eq!(
+            match_block(&config, &activates, &bid),
+            Some(MatchBlock::Dormant)
+        );
+
+        // A print at the trigger activates the first but not the second
+        assert!(activates.trigger_reached(whole(95)));
+        assert!(!never.trigger_reached(whole(95)));
+        activates.trigger_direction = crate::TriggerDirection::None as u8;
+        assert!(can_match(&config, &activates, &bid));
+        assert_eq!(
+            match_block(&config, &never, &bid),
+            Some(MatchBlock::Dormant)
+        );
+
+        // An activated order has nothing left to trigger
+        assert!(!activates.trigger_reached(whole(1)));
+    }
+
+    #[test]
+    fn displayed_size_fills_before_hidden_size_at_one_price() {
+        // Oldest first: hidden, shown, hidden, shown
+        let hidden = |id: u64| Order {
+            fully_hidden: true,
+            ..order(id, false, 100, whole(10))
+        };
+        let book = vec![
+            hidden(1),
+            order(2, false, 100, whole(10)),
+            hidden(3),
+            order(4, false, 100, whole(10)),
+        ];
+        let units = |amount: u64| U256::from(amount);
+        let sweep = |config: &MatchConfig| {
+            let mut orders = book.clone();
+            let (fills, _) = fill_incoming(
+                config,
+                &market(true, 250),
+                whole(10),
+                &mut orders,
+                &mut MatchSummary::default(),
+                |_| {},
+            );
+            taken(&fills)
+                .into_iter()
+                .map(|(id, amount, _, _)| (id, amount))
+                .collect::<Vec<_>>()
+        };
+
+        // Time priority applies within each visibility class
+        assert_eq!(
+            sweep(&MatchConfig::default()),
+            vec![(2, units(100)), (4, units(100)), (1, units(50))]
+        );
+
+        // Pro-rata shares the displayed size first, then the hidden size
+        let pro_rata = MatchConfig {
+            pro_rata: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            sweep(&pro_rata),
+            vec![
+                (2, units(100)),
+                (4, units(100)),
+                (1, units(25)),
+                (3, units(25)),
+            ]
+        );
+
+        // And when a pro-rata book scan groups a resting buy's candidates
+        let mut orders = book;
+        orders.push(Order {
+            timestamp: 0,
+            ..order(5, true, 250, whole(10))
+        });
+        canonicalize(&mut orders);
+        let fills = scan_orders(&pro_rata, &mut orders, &mut MatchSummary::default(), |_| {});
+        assert_eq!(
+            filled(&fills),
+            vec![
+                (5, 2, units(100)),
+                (5, 4, units(100)),
+                (5, 1, units(25)),
+                (5, 3, units(25)),
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_convert_through_both_tokens_decimals() {
+        let (weth, usdc, wbtc) = (
+            Address::repeat_byte(0xe7),
+            Address::repeat_byte(0x6c),
+            Address::repeat_byte(0xb8),
+        );
+        let config = MatchConfig {
+            token_decimals: vec![(weth, 18), (usdc, 6), (wbtc, 8)],
+            ..Default::default()
+        };
+        let units = |amount: u64| U256::from(amount);
+        let e = |exponent: u64| U256::from(10).pow(U256::from(exponent));
+        // Quote units a resting order on `is_buy`'s side is owed or pays
+        let owed = |base: Address, quote: Address, is_buy: bool, amount: U256, price: U256| {
+            let (token_in, token_out) = if is_buy { (quote, base) } else { (base, quote) };
+            let resting = Order {
+                token_in,
+                token_out,
+                ..order(1, is_buy, 0, price)
+            };
+            quote_owed(&config, &resting, amount, price).unwrap()
+        };
+
+        // 6/18: 3000.5 USDC per WETH is 6001 micro-USDC per 2e12 wei
+        let price = whole(3_000) + PRICE_SCALE / units(2);
+        assert_eq!(
+            unit_rate(&config, weth, usdc, price),
+            Some((units(6_001), units(2) * e(12)))
+        );
+        let one_and_a_half = units(15) * e(17);
+        for is_buy in [true, false] {
+            assert_eq!(
+                owed(weth, usdc, is_buy, one_and_a_half, price),
+                units(4_500_750_000)
+            );
+        }
+        // One wei is worth 0.000003 micro-USDC: a resting sell is paid a
+        // whole unit, a resting buy pays nothing
+        assert_eq!(owed(weth, usdc, false, units(1), price), units(1));
+        assert_eq!(owed(weth, usdc, true, units(1), price), units(0));
+
+        // 8/18: 16.25 WETH per WBTC is an exact 1.625e11 wei per satoshi
+        let price = whole(16) + PRICE_SCALE / units(4);
+        assert_eq!(
+            unit_rate(&config, wbtc, weth, price),
+            Some((units(1_625) * e(8), units(1)))
+        );
+        assert_eq!(
+            owed(wbtc, weth, false, units(50_000_000), price),
+            units(8_125) * e(15)
+        );
+
+        // 18/8: 0.06 WBTC per WETH is 3 satoshi per 5e11 wei
+        let price = PRICE_SCALE * units(6) / units(100);
+        assert_eq!(
+            unit_rate(&config, weth, wbtc, price),
+            Some((units(3), units(5) * e(11)))
+        );
+        assert_eq!(
+            owed(weth, wbtc, true, units(2) * e(18), price),
+            units(12_000_000)
+        );
+        // 1e11 wei is worth 0.6 satoshi
+        assert_eq!(owed(weth, wbtc, false, e(11), price), units(1));
+        assert_eq!(owed(weth, wbtc, true, e(11), price), units(0));
+
+        // 18/18 at 2.5: 4 units are an exact 10, 3 units round to the resting side
+        let price = whole(2) + PRICE_SCALE / units(2);
+        assert_eq!(
+            unit_rate(&config, weth, Address::repeat_byte(0x18), price),
+            Some((units(5), units(2)))
+        );
+        for is_buy in [true, false] {
+            assert_eq!(owed(BASE, QUOTE, is_buy, units(4), price), units(10));
+        }
+        assert_eq!(owed(BASE, QUOTE, false, units(3), price), units(8));
+        assert_eq!(owed(BASE, QUOTE, true, units(3), price), units(7));
+    }
+
+    #[test]
+    fn triangles_clear_rings_and_leave_residuals_resting() {
+        let [first, second, third] = <[Order; 3]>::try_from(ring()).unwrap();
+        let mut books = [
+            vec![first, leg(4, RING_A, RING_B, 50, whole(2))],
+            vec![second],
+            vec![third],
+        ];
+        let mut summary = MatchSummary::default();
+        let [a, b, c] = &mut books;
+        let rings = scan_triangles(&MatchConfig::default(), [a, b, c], &mut summary, |_| {});
+
+        // The first ring empties order 1; the C and B legs' residuals then
+        // ring again with order 4, capped by its 50 A
+        let units = |amount: u64| U256::from(amount);
+        let found: Vec<_> = rings
+            .iter()
+            .map(|(rows, results)| (*rows, legs_filled(results)))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    [0, 0, 0],
+                    vec![
+                        (2, 1, units(100), units(200)),
+                        (3, 2, units(400), units(100)),
+                        (1, 3, units(200), units(400)),
+                    ]
+                ),
+                (
+                    [1, 0, 0],
+                    vec![
+                        (2, 4, units(50), units(100)),
+                        (3, 2, units(200), units(50)),
+                        (4, 3, units(100), units(200)),
+                    ]
+                ),
+            ]
+        );
+        let left: Vec<U256> = books.iter().flatten().map(|o| o.amount).collect();
+        assert_eq!(left, vec![units(0), units(0), units(400), units(200)]);
+        assert_eq!((summary.comparisons, summary.matches_found), (2, 6));
+    }
+
+    #[test]
+    fn a_triangle_one_unit_short_of_its_limits_never_clears() {
+        let mut legs = ring();
+        legs[2].limit_price = whole(2) + U256::from(1);
+        let [first, second, third] = <[Order; 3]>::try_from(legs).unwrap();
+        let mut books = [vec![first], vec![second], vec![third]];
+        let mut summary = MatchSummary::default();
+        let [a, b, c] = &mut books;
+        let rings = scan_triangles(&MatchConfig::default(), [a, b, c], &mut summary, |_| {});
+
+        assert!(rings.is_empty());
+        assert_eq!((summary.comparisons, summary.matches_found), (1, 0));
+        let left: Vec<U256> = books.iter().flatten().map(|o| o.amount).collect();
+        assert_eq!(
+            left,
+            vec![U256::from(100), U256::from(1_000), U256::from(500)]
+        );
+    }
+
+    #[test]
+    fn a_collar_keeps_an_aggressive_limit_off_a_distant_quote() {
+        let config = MatchConfig {
+            reference_prices: vec![(BASE, QUOTE, whole(100))],
+            ..Default::default()
+        };
+        // A buy at 200 used as a market order, collared to 5% of the last trade
+        let collared = Order {
+            collar_bps: 500,
+            ..order(1, true, 100, whole(200))
+        };
+        let distant = order(2, false, 100, whole(150));
+        assert_eq!(
+            match_block(&config, &collared, &distant),
+            Some(MatchBlock::OutsideCollar)
+        );
+
+        // Without the collar the same pair fills at 175
+        let uncollared = Order {
+            collar_bps: 0,
+            ..collared.clone()
+        };
+        let result = execute_single_match(&config, &uncollared, &distant).unwrap();
+        assert_eq!(result.execution_price, whole(175));
+
+        // A buy at 104 meeting a sell at 100 prints 102, inside the collar
+        let near = Order {
+            limit_price: whole(104),
+            ..collared.clone()
+        };
+        assert!(can_match(&config, &near, &order(3, false, 100, whole(100))));
+
+        // In a scan the collared buy is left resting
+        let mut orders = vec![collared, distant];
+        let mut summary = MatchSummary::default();
+        let fills = scan_orders(&config, &mut orders, &mut summary, |_| {});
+        assert!(fills.is_empty());
+        assert_eq!(orders[0].amount, U256::from(100));
+        assert_eq!(summary.skipped[MatchBlock::OutsideCollar as usize], 1);
+    }
+
+    #[test]
+    fn an_iceberg_shows_one_tranche_per_pass_and_requeues_when_it_empties() {
+        let iceberg = Order {
+            display_amount: U256::from(100),
+            ..order(1, false, 1_000, whole(10))
+        };
+        let mut orders = vec![
+            Order {
+                timestamp: 8,
+                ..order(3, true, 350, whole(10))
+            },
+            iceberg,
+            Order {
+                timestamp: 5,
+                ..order(2, false, 100, whole(10))
+            },
+        ];
+        // One matching pass as the contract runs it at `now`
+        let pass = |orders: &mut Vec<Order>, now: u64| {
+            canonicalize(orders);
+            let hidden = hide_reserves(orders);
+            let fills = scan_orders(
+                &MatchConfig::default(),
+                orders,
+                &mut MatchSummary::default(),
+                |_| {},
+            );
+            let refreshed = restore_reserves(orders, &hidden, now);
+            (filled(&fills), refreshed)
+        };
+        let units = |amount: u64| U256::from(amount);
+        let iceberg_of = |orders: &[Order]| orders.iter().find(|o| o.id == 1).unwrap().clone();
+
+        // Only the 100 tranche fills; sell 2 covers another 100 of the buy
+        let (fills, refreshed) = pass(&mut orders, 10);
+        assert_eq!(fills, vec![(3, 1, units(100)), (3, 2, units(100))]);
+        assert_eq!(refreshed, vec![0]);
+        let iceberg = iceberg_of(&orders);
+        assert_eq!(iceberg.amount, units(900));
+        assert_eq!(iceberg.visible_amount(), units(100));
+        assert_eq!(iceberg.amount - iceberg.visible_amount(), units(800));
+        // The fresh tranche queues behind everything already resting
+        assert_eq!(iceberg.timestamp, 10);
+        canonicalize(&mut orders);
+        let queue: Vec<u64> = orders.iter().map(|o| o.id).collect();
+        assert_eq!(queue, vec![2, 3, 1]);
+
+        // Each later pass takes at most one more tranche
+        let (fills, _) = pass(&mut orders, 20);
+        assert_eq!(fills, vec![(3, 1, units(100))]);
+        let (fills, refreshed) = pass(&mut orders, 30);
+        assert_eq!(fills, vec![(3, 1, units(50))]);
+        assert!(refreshed.is_empty());
+
+        // 250 of the 350 came from the iceberg; an uneven 50 shows first
+        let iceberg = iceberg_of(&orders);
+        assert_eq!(iceberg.amount, units(750));
+        assert_eq!(iceberg.visible_amount(), units(50));
+        assert_eq!(iceberg.timestamp, 20);
+    }
+
+    /// A small random book: prices near 10, a few traders (so some pairs
+    /// are self-trades), and the odd minimum fill, iceberg or hidden order
+    fn random_book(words: &mut impl Iterator<Item = u64>) -> Vec<Order> {
+        (1..=8)
+            .map(|id| {
+                let word = words.next().unwrap();
+                let mut order = order(
+                    id,
+                    word & 1 == 0,
+                    10 + word % 90,
+                    whole(8 + (word >> 8) % 5),
+                );
+                order.trader = Address::repeat_byte(1 + (word >> 16) as u8 % 4);
+                match (word >> 24) % 6 {
+                    0 => order.min_fill_amount = U256::from(40),
+                    1 => order.display_amount = U256::from(5),
+                    2 => order.fully_hidden = true,
+                    _ => {}
+                }
+                order
+            })
+            .collect()
+    }
+
+    #[test]
+    fn eligibility_and_serve_order_agree_with_the_fills_made() {
+        let config = MatchConfig::default();
+        let mut words = words(0x293);
+        for _ in 0..200 {
+            let book = random_book(&mut words);
+
+            // A book scan's first fill is the first pair, in scan order,
+            // that `pair_eligibility` calls ok, at the amount and price it
+            // reports
+            let mut scanned = book.clone();
+            hide_reserves(&mut scanned);
+            let fills = scan_orders(&config, &mut scanned, &mut MatchSummary::default(), |_| {});
+            let first_ok = (0..book.len())
+                .flat_map(|i| ((i + 1)..book.len()).map(move |j| (i, j)))
+                .find_map(
+                    |(i, j)| match pair_eligibility(&config, &book[i], &book[j]) {
+                        Some((true, 0, amount, price)) => Some((i, j, amount, price)),
+                        Some((false, _, _, _)) => None,
+                        other => panic!("unexpected eligibility {other:?}"),
+                    },
+                );
+            let first_fill = fills
+                .first()
+                .map(|(i, j, result)| (*i, *j, result.amount, result.execution_price));
+            assert_eq!(first_fill, first_ok);
+
+            // An incoming order takes resting orders in `serve_order` order
+            for is_buy in [true, false] {
+                let mut resting = book.clone();
+                let (fills, _) = fill_incoming(
+                    &config,
+                    &market(is_buy, 10_000),
+                    if is_buy { U256::MAX } else { U256::ZERO },
+                    &mut resting,
+                    &mut MatchSummary::default(),
+                    |_| {},
+                );
+                for pair in fills.windows(2) {
+                    let (a, b) = (&book[pair[0].0], &book[pair[1].0]);
+                    assert_eq!(serve_order(a, b), Ordering::Less);
+                }
+            }
+        }
+    }
+
+    /// Fills as (buy id, sell id, amount, price), independent of positions
+    fn fills_by_id(fills: &[(usize, usize, MatchResult)]) -> Vec<(u64, u64, U256, U256)> {
+        fills
+            .iter()
+            .map(|(_, _, r)| (r.buy_order_id, r.sell_order_id, r.amount, r.execution_price))
+            .collect()
+    }
+
+    #[test]
+    fn crossing_candidates_drop_dead_and_distant_orders_without_changing_fills() {
+        let config = MatchConfig::default();
+        let mut book: Vec<Order> = Vec::new();
+        for id in 1..=60 {
+            let mut order = match id % 6 {
+                // Far from the touch on either side
+                0 => order(id, true, 100, whole(5)),
+                1 => order(id, false, 100, whole(20)),
+                // Filled out or waiting on a cancel
+                2 => order(id, true, 0, whole(15)),
+                3 => order(id, false, 100, whole(8)),
+                _ => order(id, id % 2 == 0, 100, whole(9)),
+            };
+            match id % 6 {
+                3 => order.cancel_pending = true,
+                4 | 5 if id > 12 => order.limit_price = whole(if order.is_buy { 4 } else { 21 }),
+                _ => {}
+            }
+            book.push(order);
+        }
+        // With orders 4, 5, 10 and 11 at 9, the only live crossing orders
+        book.push(order(61, false, 50, whole(10)));
+        book.push(order(62, true, 120, whole(11)));
+        canonicalize(&mut book);
+
+        let fingerprints: Vec<U256> = book.iter().map(fingerprint).collect();
+        let candidates = crossing_candidates(&fingerprints);
+        let ids: Vec<u64> = candidates.iter().map(|&i| book[i].id).collect();
+        assert_eq!(ids, vec![4, 5, 10, 11, 61, 62]);
+
+        let mut full = book.clone();
+        let full_fills = scan_orders(&config, &mut full, &mut MatchSummary::default(), |_| {});
+        let mut subset: Vec<Order> = candidates.iter().map(|&i| book[i].clone()).collect();
+        let subset_fills = scan_orders(&config, &mut subset, &mut MatchSummary::default(), |_| {});
+        assert!(!full_fills.is_empty());
+        assert_eq!(fills_by_id(&subset_fills), fills_by_id(&full_fills));
+    }
+
+    #[test]
+    fn an_order_with_a_cancel_pending_is_never_filled() {
+        let config = MatchConfig::default();
+        let mut cancelling = order(1, false, 100, whole(10));
+        cancelling.cancel_pending = true;
+        let book = vec![
+            cancelling,
+            order(2, false, 100, whole(10)),
+            order(3, true, 100, whole(10)),
+        ];
+
+        assert_eq!(
+            match_block(&config, &book[0], &book[2]),
+            Some(MatchBlock::Dormant)
+        );
+
+        // The scan moves on to the next sell
+        let mut scanned = book.clone();
+        let fills = scan_orders(&config, &mut scanned, &mut MatchSummary::default(), |_| {});
+        assert_eq!(filled(&fills), vec![(3, 2, U256::from(100))]);
+        assert_eq!(scanned[0].amount, U256::from(100));
+
+        // An incoming buy passes over it too
+        let mut resting = book.clone();
+        let (fills, left) = fill_incoming(
+            &config,
+            &market(true, 150),
+            U256::MAX,
+            &mut resting,
+            &mut MatchSummary::default(),
+            |_| {},
+        );
+        assert_eq!(
+            taken(&fills),
+            vec![(2, U256::from(100), whole(10), U256::from(1_000))]
+        );
+        assert_eq!(left, U256::from(50));
+
+        // And the pre-filter counts it as dead
+        let fingerprints: Vec<U256> = book.iter().map(fingerprint).collect();
+        assert_eq!(crossing_candidates(&fingerprints), vec![1, 2]);
+    }
+}
@@ -0,0 +1,207 @@
+//! Off-chain shadow matcher (`offchain` feature)
+//!
+//! Replays the contract's matching pass over a book snapshot, using the same
+//! core as `execute_match`, so operators can see the fills a pass would
+//! produce before paying for it or check one after the fact.
+
+use alloc::vec::Vec;
+
+use crate::matching::{canonicalize, hide_reserves, scan_orders, split_books, MatchConfig};
+use crate::{index_to_u64, index_to_usize, MatchResult, MatchSummary, Order};
+
+/// Reference matcher mirroring the on-chain engine
+#[derive(Clone, Debug, Default)]
+pub struct OffchainMatcher {
+    config: MatchConfig,
+}
+
+impl OffchainMatcher {
+    /// Create a matcher using the contract's current settings: `fee_bps()`,
+    /// `allow_self_trade()`, the flagged trader pairs in strict wash-trade
+    /// mode, every snapshot token's `decimals_of()`, the circuit breaker
+    /// threshold with each direction's `last_trade_price()` (collared orders
+    /// need those prices even with the breaker off), and the block
+    /// timestamp to match at. Set `pro_rata` to replay a pro-rata market;
+    /// the snapshot is matched with one allocation mode throughout.
+    pub fn new(config: MatchConfig) -> Self {
+        Self { config }
+    }
+
+    /// Compute the fills `execute_match` would produce for a snapshot
+    ///
+    /// `orders` must be every stored order with books in listing order, which
+    /// is what `get_orders` returns. Orders within a book may come in any
+    /// order: each book is sorted into time priority before it is scanned,
+    /// as the contract does. Markets outside their trading
+    /// hours are not filtered here; drop them from the snapshot first. Hidden
+    /// orders are missing from public snapshots, so fills against them can't
+    /// be predicted.
+    /// `gas_used` is left at zero since no host is metering the pass.
+    pub fn match_snapshot(&self, orders: &[Order]) -> (Vec<MatchResult>, MatchSummary) {
+        let mut summary = MatchSummary::default();
+        let mut matches: Vec<MatchResult> = Vec::new();
+
+        for mut book in split_books(orders.iter().cloned()) {
+            canonicalize(&mut book);
+            hide_reserves(&mut book);
+            let fills = scan_orders(&self.config, &mut book, &mut summary, |_| {});
+            matches.extend(fills.into_iter().map(|(_, _, result)| result));
+        }
+
+        (matches, summary)
+    }
+
+    /// `match_snapshot` after shuffling each book's orders with `seed`
+    ///
+    /// A determinism check: for any seed the fills must equal those of the
+    /// unshuffled snapshot, since scans never depend on where an order sits
+    /// in storage. Book listing order is kept so results stay comparable.
+    pub fn match_shuffled(&self, orders: &[Order], seed: u64) -> (Vec<MatchResult>, MatchSummary) {
+        let mut rng = SplitMix64(seed);
+        let mut shuffled: Vec<Order> = Vec::with_capacity(orders.len());
+        for mut book in split_books(orders.iter().cloned()) {
+            // Fisher-Yates
+            for i in (1..book.len()).rev() {
+                let j = index_to_usize(rng.next() % (index_to_u64(i) + 1));
+                book.swap(i, j);
+            }
+            shuffled.extend(book);
+        }
+        self.match_snapshot(&shuffled)
+    }
+}
+
+/// Small seeded generator for `match_shuffled`; quality only needs to be
+/// good enough to reach varied permutations
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PRICE_SCALE;
+    use alloc::vec;
+    use alloy_sol_types::{sol_data, SolType};
+    use stylus_sdk::alloy_primitives::{Address, U256};
+
+    /// An order selling `token_in` for `token_out` at `price` (quote per
+    /// base, in whole PRICE_SCALE units)
+    fn order(id: u64, market: (Address, Address), is_buy: bool, amount: u64, price: u64) -> Order {
+        let (base, quote) = market;
+        let (token_in, token_out) = if is_buy { (quote, base) } else { (base, quote) };
+        Order {
+            id,
+            trader: Address::from_word(U256::from(id).into()),
+            token_in,
+            token_out,
+            amount: U256::from(amount),
+            limit_price: PRICE_SCALE * U256::from(price),
+            is_buy,
+            timestamp: id,
+            sequence: id,
+            ..Default::default()
+        }
+    }
+
+    /// Two books, listed first-seen, with orders stored out of time priority
+    /// and an iceberg in the first
+    fn books() -> Vec<Vec<Order>> {
+        let eth_usd = (Address::repeat_byte(0xe1), Address::repeat_byte(0xd0));
+        let btc_usd = (Address::repeat_byte(0xb1), Address::repeat_byte(0xd0));
+        let mut iceberg = order(2, eth_usd, false, 900, 10);
+        iceberg.display_amount = U256::from(300);
+        vec![
+            vec![
+                order(5, eth_usd, true, 700, 11),
+                iceberg,
+                order(1, eth_usd, true, 400, 12),
+                order(7, eth_usd, false, 250, 9),
+            ],
+            vec![
+                order(6, btc_usd, false, 100, 30),
+                order(3, btc_usd, true, 60, 31),
+                order(4, btc_usd, true, 80, 29),
+            ],
+        ]
+    }
+
+    /// Fills compared by their ABI encoding, field for field
+    fn encoded(fills: &[MatchResult]) -> Vec<u8> {
+        sol_data::Array::<MatchResult>::abi_encode(fills)
+    }
+
+    /// A fill as `execute_match` reports it, price in tenths of a whole
+    /// PRICE_SCALE unit
+    fn fill(buy: u64, sell: u64, tenths: u64, amount: u64, quote: u64, fee: u64) -> MatchResult {
+        MatchResult {
+            buy_order_id: buy,
+            sell_order_id: sell,
+            execution_price: PRICE_SCALE * U256::from(tenths) / U256::from(10),
+            amount: U256::from(amount),
+            quote_amount: U256::from(quote),
+            fee: U256::from(fee),
+            gas_used: U256::ZERO,
+            flagged: false,
+        }
+    }
+
+    #[test]
+    fn snapshot_fills_match_hand_computed_values() {
+        let config = MatchConfig {
+            fee_bps: 100,
+            ..Default::default()
+        };
+        let books = books();
+        // Interleave the books the way a storage listing may return them
+        let mut snapshot: Vec<Order> = Vec::new();
+        for i in 0..4 {
+            snapshot.extend(books.iter().filter_map(|book| book.get(i).cloned()));
+        }
+
+        let (matches, summary) = OffchainMatcher::new(config).match_snapshot(&snapshot);
+        // ETH/USD in time priority is 1, 2, 5, 7. Buy 1 takes the iceberg's
+        // 300 tranche at the midpoint of 12 and 10, then 100 of sell 7 at the
+        // midpoint of 12 and 9; buy 5 takes the other 150 of sell 7 at 10. In
+        // BTC/USD buy 3 takes 60 of sell 6 at 30.5, and buy 4 at 29 doesn't
+        // cross it. Fees are 1% of the base filled, rounded down.
+        let expected = [
+            fill(1, 2, 110, 300, 3_300, 3),
+            fill(1, 7, 105, 100, 1_050, 1),
+            fill(5, 7, 100, 150, 1_500, 1),
+            fill(3, 6, 305, 60, 1_830, 0),
+        ];
+        assert_eq!(encoded(&matches), encoded(&expected));
+        assert_eq!(
+            (
+                summary.orders_scanned,
+                summary.comparisons,
+                summary.matches_found
+            ),
+            (7, 7, 4)
+        );
+        // Two same-side pairs and one that doesn't cross
+        assert_eq!(summary.skipped, vec![0, 2, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn shuffled_snapshots_match_the_same_fills() {
+        let matcher = OffchainMatcher::new(MatchConfig::default());
+        let snapshot: Vec<Order> = books().into_iter().flatten().collect();
+        let expected = encoded(&matcher.match_snapshot(&snapshot).0);
+        for seed in 0..32 {
+            assert_eq!(
+                encoded(&matcher.match_shuffled(&snapshot, seed).0),
+                expected
+            );
+        }
+    }
+}
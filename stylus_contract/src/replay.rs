@@ -0,0 +1,249 @@
+//! Action replay for bug reports (`testing` feature)
+//!
+//! A bug report can attach a byte string of encoded actions; replaying it
+//! runs the same submissions, cancels and matching passes through the
+//! contract's matching core on a fresh in-memory book and returns a digest
+//! of the final state, so two runs agree exactly when they reached the same
+//! orders and fills.
+//!
+//! The replay covers the matching core only. Submission checks, exposure,
+//! the internal balance ledger and market sessions live in contract storage
+//! and are not modelled, and ids are submission sequences rather than the
+//! hashed ids the contract issues.
+
+use alloc::vec::Vec;
+use alloy_sol_types::{sol, sol_data, SolType};
+use stylus_sdk::{
+    alloy_primitives::{Address, B256, U256},
+    crypto::keccak,
+};
+
+use crate::matching::{canonicalize, scan_orders, split_books, MatchConfig};
+use crate::{MatchResult, MatchSummary, Order};
+
+sol! {
+    /// One step of a replay script; fields a kind doesn't use are ignored
+    #[derive(Debug, Default)]
+    struct ReplayAction {
+        /// ReplayKind as u8
+        uint8 kind;
+        address sender;
+        /// Block timestamp the action runs at
+        uint64 timestamp;
+        address token_in;
+        address token_out;
+        uint256 amount;
+        uint256 limit_price;
+        bool is_buy;
+        uint256 min_fill_amount;
+        /// Order a Cancel targets (its submission sequence)
+        uint64 order_id;
+    }
+}
+
+/// abi.encode(ReplayAction[]), the format `replay_actions` reads
+pub type ReplayScript = sol_data::Array<ReplayAction>;
+
+/// abi.encode(Order[] by id, MatchResult[] in execution order), hashed into
+/// the digest `replay_actions` returns
+type StateDigestPreimage = (sol_data::Array<Order>, sol_data::Array<MatchResult>);
+
+/// What a ReplayAction does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ReplayKind {
+    /// Place an order for `sender`
+    Submit = 0,
+    /// Cancel `order_id` if `sender` placed it and it is still open
+    Cancel = 1,
+    /// Run a matching pass over every book
+    Match = 2,
+}
+
+/// Why a script could not be replayed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The bytes are not an ABI-encoded ReplayAction[]
+    Malformed,
+    /// Action at this position has an unknown kind
+    UnknownAction(usize),
+}
+
+/// Encode `actions` as a script for `replay_actions`
+///
+/// Tests build failing sequences with this; `decode_actions` reads the
+/// bytes back unchanged, so a script attached to a bug report replays
+/// exactly.
+pub fn encode_actions(actions: &[ReplayAction]) -> Vec<u8> {
+    ReplayScript::abi_encode(actions)
+}
+
+/// Decode a script written by `encode_actions`
+pub fn decode_actions(encoded: &[u8]) -> Result<Vec<ReplayAction>, ReplayError> {
+    ReplayScript::abi_decode(encoded, true).map_err(|_| ReplayError::Malformed)
+}
+
+/// Replay `encoded` against an empty book under `config`, returning the
+/// digest of the final state
+///
+/// `config.now` is replaced by each action's timestamp.
+pub fn replay_actions(config: &MatchConfig, encoded: &[u8]) -> Result<B256, ReplayError> {
+    let actions = decode_actions(encoded)?;
+    let mut replay = Replay {
+        config: config.clone(),
+        ..Default::default()
+    };
+    for (position, action) in actions.into_iter().enumerate() {
+        replay.config.now = action.timestamp;
+        match action.kind {
+            kind if kind == ReplayKind::Submit as u8 => replay.submit(action),
+            kind if kind == ReplayKind::Cancel as u8 => {
+                replay.cancel(action.sender, action.order_id)
+            }
+            kind if kind == ReplayKind::Match as u8 => replay.run_match(),
+            _ => return Err(ReplayError::UnknownAction(position)),
+        }
+    }
+    Ok(replay.digest())
+}
+
+/// In-memory book state a script runs against
+#[derive(Default)]
+struct Replay {
+    config: MatchConfig,
+    /// Open orders, books in first-listed order
+    orders: Vec<Order>,
+    fills: Vec<MatchResult>,
+    submitted: u64,
+}
+
+impl Replay {
+    fn submit(&mut self, action: ReplayAction) {
+        self.submitted += 1;
+        self.orders.push(Order {
+            id: self.submitted,
+            trader: action.sender,
+            token_in: action.token_in,
+            token_out: action.token_out,
+            amount: action.amount,
+            limit_price: action.limit_price,
+            is_buy: action.is_buy,
+            timestamp: action.timestamp,
+            min_fill_amount: action.min_fill_amount,
+            sequence: self.submitted,
+            ..Default::default()
+        });
+    }
+
+    fn cancel(&mut self, sender: Address, order_id: u64) {
+        self.orders
+            .retain(|order| order.id != order_id || order.trader != sender);
+    }
+
+    /// One pass over every book, as `execute_match` runs it
+    fn run_match(&mut self) {
+        let mut summary = MatchSummary::default();
+        for mut book in split_books(core::mem::take(&mut self.orders)) {
+            canonicalize(&mut book);
+            let fills = scan_orders(&self.config, &mut book, &mut summary, |_| {});
+            self.fills
+                .extend(fills.into_iter().map(|(_, _, result)| result));
+            self.orders
+                .extend(book.into_iter().filter(|order| order.amount != U256::ZERO));
+        }
+    }
+
+    fn digest(mut self) -> B256 {
+        self.orders.sort_by_key(|order| order.id);
+        keccak(StateDigestPreimage::abi_encode(&(self.orders, self.fills)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PRICE_SCALE;
+    use alloc::vec;
+
+    const BASE: Address = Address::repeat_byte(0xba);
+    const QUOTE: Address = Address::repeat_byte(0x9e);
+
+    fn submit(sender: u8, timestamp: u64, is_buy: bool, amount: u64, price: u64) -> ReplayAction {
+        let (token_in, token_out) = if is_buy { (QUOTE, BASE) } else { (BASE, QUOTE) };
+        ReplayAction {
+            kind: ReplayKind::Submit as u8,
+            sender: Address::repeat_byte(sender),
+            timestamp,
+            token_in,
+            token_out,
+            amount: U256::from(amount),
+            limit_price: PRICE_SCALE * U256::from(price),
+            is_buy,
+            ..Default::default()
+        }
+    }
+
+    fn action(kind: ReplayKind, sender: u8, timestamp: u64, order_id: u64) -> ReplayAction {
+        ReplayAction {
+            kind: kind as u8,
+            sender: Address::repeat_byte(sender),
+            timestamp,
+            order_id,
+            ..Default::default()
+        }
+    }
+
+    /// Two crossing orders, a cancelled one, a pass, and an order left resting
+    fn script() -> Vec<ReplayAction> {
+        vec![
+            submit(1, 10, false, 500, 4),
+            submit(2, 11, true, 300, 5),
+            submit(3, 12, true, 100, 6),
+            action(ReplayKind::Cancel, 3, 13, 3),
+            action(ReplayKind::Match, 9, 14, 0),
+            submit(4, 15, true, 50, 3),
+        ]
+    }
+
+    #[test]
+    fn scripts_round_trip() {
+        let encoded = encode_actions(&script());
+        let decoded = decode_actions(&encoded).unwrap();
+        assert_eq!(decoded.len(), script().len());
+        assert_eq!(encode_actions(&decoded), encoded);
+        assert_eq!(
+            decode_actions(&encoded[1..]).err(),
+            Some(ReplayError::Malformed)
+        );
+    }
+
+    #[test]
+    fn digest_depends_only_on_the_script() {
+        let config = MatchConfig {
+            fee_bps: 10,
+            ..Default::default()
+        };
+        let encoded = encode_actions(&script());
+        let digest = replay_actions(&config, &encoded).unwrap();
+        assert_eq!(replay_actions(&config, &encoded), Ok(digest));
+
+        // A cancel from someone other than the order's trader does nothing,
+        // so the cancelled order fills instead
+        let mut changed = script();
+        changed[3].sender = Address::repeat_byte(4);
+        assert_ne!(
+            replay_actions(&config, &encode_actions(&changed)),
+            Ok(digest)
+        );
+    }
+
+    #[test]
+    fn unknown_actions_are_reported_by_position() {
+        let mut actions = script();
+        actions[4].kind = 7;
+        assert_eq!(
+            replay_actions(&MatchConfig::default(), &encode_actions(&actions)),
+            Err(ReplayError::UnknownAction(4))
+        );
+    }
+}